@@ -0,0 +1,252 @@
+use std::{collections::{HashMap, HashSet, hash_map::DefaultHasher}, hash::{Hash, Hasher}};
+
+use gl::types::GLenum;
+
+use crate::{program::{Program, ProgramBuilder, compile_includes}, shader::Shader, preprocessor::FileLoader};
+
+
+fn text_hash(text: &str) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    text.hash(&mut hasher);
+    hasher.finish()
+}
+
+struct Stage {
+    path: String,
+    shader_type: GLenum,
+    shader: Shader,
+    dependencies: HashMap<String, u64>, // File -> hash of its text at the moment of compilation
+}
+
+impl Stage {
+    fn compile(loader: &FileLoader, path: &str, shader_type: GLenum) -> Result<Stage, String> {
+        // Hashes are taken from the texts that were compiled, so changes made meanwhile are not missed
+        let (includes, sources) = loader.load_file_with_sources(path)?;
+        let shader = compile_includes(&includes, shader_type, loader.error_formatter())?;
+
+        let dependencies = sources.iter()
+            .map(|(file, text)| (file.clone(), text_hash(text)))
+            .collect();
+
+        Ok(Stage { path: path.to_owned(), shader_type, shader, dependencies })
+    }
+}
+
+/// Finds source files, which changed since the last rebuild attempt
+#[derive(Default)]
+struct SourceWatch {
+    attempted: HashMap<String, Option<u64>>, // File -> hash of its text at the last rebuild attempt (`None` if it could not be loaded)
+}
+
+impl SourceWatch {
+    /// Files from `dependencies` (file -> hash of the text a stage was compiled from) with their current hashes,
+    /// if they differ from the hashes at the last attempt (or at compilation, if there was no attempt yet)
+    fn changed_files<'a>(&self, loader: &FileLoader, dependencies: impl Iterator<Item = (&'a String, &'a u64)>) -> HashMap<String, Option<u64>> {
+        let mut changed = HashMap::new();
+        let mut checked = HashSet::new();
+
+        for (file, hash) in dependencies {
+            if !checked.insert(file) {
+                continue;
+            }
+
+            // File was removed or is broken - it is changed too, so recompilation reports it
+            let current = loader.basic_load_file(file).ok().map(|text| text_hash(&text));
+            if current != self.attempted.get(file).copied().unwrap_or(Some(*hash)) {
+                changed.insert(file.clone(), current);
+            }
+        }
+
+        changed
+    }
+
+    /// Remembers texts of the rebuild attempt, so failed ones are not retried until they change again
+    fn record_attempt(&mut self, changed_files: HashMap<String, Option<u64>>) {
+        self.attempted.extend(changed_files);
+    }
+}
+
+/// Called after each rebuild attempt of [`HotProgram`] with its outcome
+pub type ReloadHandler = dyn Fn(Result<(), &str>);
+
+/// Program, that can be rebuilt when its source files change.
+///
+/// Changes are detected by reloading the sources through the loader, so any protocol is supported.
+/// Only stages, which depend on changed files, are recompiled.
+///
+/// ```rust,no_run
+/// use shader_loader::{hot_reload::HotProgram, preprocessor::FileLoader};
+///
+/// let mut program = HotProgram::new(FileLoader::new(), &[
+///     ("shaders/main.vert", gl::VERTEX_SHADER),
+///     ("shaders/main.frag", gl::FRAGMENT_SHADER),
+/// ]).unwrap();
+/// program.on_reload(Box::new(|result| if let Err(error) = result {
+///     eprintln!("{error}");
+/// }));
+///
+/// loop {
+///     // Errors are reported by the handler, previous program is kept
+///     let _ = program.poll_reload();
+///     program.program().use_program();
+///     // draw ...
+/// }
+/// ```
+pub struct HotProgram {
+    loader: FileLoader,
+    stages: Vec<Stage>,
+    program: Program,
+    on_reload: Option<Box<ReloadHandler>>,
+    watch: SourceWatch,
+}
+
+impl HotProgram {
+    pub fn new(loader: FileLoader, files: &[(&str, GLenum)]) -> Result<HotProgram, String> {
+        let stages: Vec<Stage> = files.iter()
+            .map(|(path, shader_type)| Stage::compile(&loader, path, *shader_type))
+            .collect::<Result<_, _>>()?;
+
+        let program = Self::link(stages.iter().map(|stage| &stage.shader))?;
+        Ok(HotProgram { loader, stages, program, on_reload: None, watch: SourceWatch::default() })
+    }
+
+    pub fn program(&self) -> &Program {
+        &self.program
+    }
+
+    /// Sets the handler, which is called from `poll_reload` each time it tries to rebuild the program
+    /// (e.g. to show compilation errors in UI). Failed rebuild is not retried until the files change again,
+    /// so the same error is reported once.
+    pub fn on_reload(&mut self, handler: Box<ReloadHandler>) {
+        self.on_reload = Some(handler);
+    }
+
+    /// Checks source files for changes and rebuilds the program if there are any.
+    /// Returns whether the program was rebuilt. On error previous program is kept.
+    pub fn poll_reload(&mut self) -> Result<bool, String> {
+        let dependencies = self.stages.iter().flat_map(|stage| stage.dependencies.iter());
+        let changed_files = self.watch.changed_files(&self.loader, dependencies);
+        if changed_files.is_empty() {
+            return Ok(false);
+        }
+
+        let files: Vec<String> = changed_files.keys().cloned().collect();
+        let result = self.rebuild(&files);
+        self.watch.record_attempt(changed_files);
+
+        if let Some(handler) = &self.on_reload {
+            handler(result.as_ref().map(|_| ()).map_err(|error| error.as_str()));
+        }
+        result.map(|_| true)
+    }
+
+    fn rebuild(&mut self, changed_files: &[String]) -> Result<(), String> {
+        self.loader.clear_cache();
+        let mut new_stages: HashMap<usize, Stage> = HashMap::new();
+        for (i, stage) in self.stages.iter().enumerate() {
+            if changed_files.iter().any(|file| stage.dependencies.contains_key(file)) {
+                new_stages.insert(i, Stage::compile(&self.loader, &stage.path, stage.shader_type)?);
+            }
+        }
+
+        let shaders = self.stages.iter()
+            .enumerate()
+            .map(|(i, stage)| &new_stages.get(&i).unwrap_or(stage).shader);
+        self.program = Self::link(shaders)?;
+
+        for (i, stage) in new_stages {
+            self.stages[i] = stage;
+        }
+
+        Ok(())
+    }
+
+    fn link<'a>(shaders: impl Iterator<Item = &'a Shader>) -> Result<Program, String> {
+        shaders
+            .fold(ProgramBuilder::new(), |builder, shader| builder.attach(shader))
+            .link()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{cell::RefCell, rc::Rc};
+
+    use super::*;
+
+    // Hashes of `main.glsl` as a stage compiled from `text` has them
+    fn dependencies(text: &str) -> HashMap<String, u64> {
+        HashMap::from([("mem://main.glsl".to_owned(), text_hash(text))])
+    }
+
+    fn loader(text: &Rc<RefCell<&'static str>>) -> FileLoader {
+        let text = text.clone();
+        let mut loader = FileLoader::new();
+        loader.add_protocol("mem".to_owned(), move |_: &str| Ok(text.borrow().to_string())).unwrap();
+        loader
+    }
+
+    #[test]
+    fn changes_are_found_by_hash() {
+        let text = Rc::new(RefCell::new("float v1;"));
+        let loader = loader(&text);
+        let watch = SourceWatch::default();
+
+        assert!(watch.changed_files(&loader, dependencies("float v1;").iter()).is_empty());
+
+        *text.borrow_mut() = "float v2;";
+        let changed = watch.changed_files(&loader, dependencies("float v1;").iter());
+        assert_eq!(changed, HashMap::from([("mem://main.glsl".to_owned(), Some(text_hash("float v2;")))]));
+    }
+
+    #[test]
+    fn failed_rebuild_is_attempted_once_per_change() {
+        let text = Rc::new(RefCell::new("float v1;"));
+        let loader = loader(&text);
+        let compiled = dependencies("float v1;");
+        let mut watch = SourceWatch::default();
+
+        // Same as `poll_reload` with a rebuild, which always fails, so `compiled` hashes stay the same
+        let mut attempts = 0;
+        let mut poll = |watch: &mut SourceWatch| {
+            let changed = watch.changed_files(&loader, compiled.iter());
+            if !changed.is_empty() {
+                attempts += 1;
+                watch.record_attempt(changed);
+            }
+        };
+
+        poll(&mut watch);
+        *text.borrow_mut() = "float broken";
+        for _ in 0..3 {
+            poll(&mut watch);
+        }
+        *text.borrow_mut() = "float still_broken";
+        for _ in 0..3 {
+            poll(&mut watch);
+        }
+        // Reverting to the compiled text is a change too
+        *text.borrow_mut() = "float v1;";
+        poll(&mut watch);
+
+        assert_eq!(attempts, 3);
+    }
+
+    #[test]
+    fn unloadable_file_is_reported_once() {
+        let text = Rc::new(RefCell::new("float v1;"));
+        let loader = loader(&text);
+        let compiled = dependencies("float v1;");
+        let mut watch = SourceWatch::default();
+
+        // Empty file can not be loaded, like a removed one
+        *text.borrow_mut() = "";
+        let changed = watch.changed_files(&loader, compiled.iter());
+        assert_eq!(changed, HashMap::from([("mem://main.glsl".to_owned(), None)]));
+        watch.record_attempt(changed);
+        assert!(watch.changed_files(&loader, compiled.iter()).is_empty());
+
+        *text.borrow_mut() = "float v2;";
+        assert_eq!(watch.changed_files(&loader, compiled.iter()).len(), 1);
+    }
+}
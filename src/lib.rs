@@ -1,74 +1,385 @@
-use std::{ffi::CString, fmt::Display};
+use std::{fmt::Display, hash::{Hash, Hasher}, ops::Index, path::PathBuf};
 
 use crate::preprocessor::get_protocol_and_path;
 
+#[cfg(feature = "gl")]
 pub mod shader;
+#[cfg(feature = "gl")]
 pub mod program;
+#[cfg(feature = "gl")]
+pub mod hot_reload;
 pub mod preprocessor;
 
-fn create_whitespace_cstring(len: usize) -> CString {
+/// Makes GL object wrappers `!Send` and `!Sync`: GL objects belong to the context of the thread they were created on
+#[cfg(feature = "gl")]
+pub(crate) type NotSend = std::marker::PhantomData<*const ()>;
+
+#[cfg(feature = "gl")]
+fn create_whitespace_cstring(len: usize) -> std::ffi::CString {
     let mut buffer: Vec<u8> = Vec::with_capacity(len as usize + 1);
     buffer.extend([b' '].iter().cycle().take(len as usize));
-    unsafe { CString::from_vec_unchecked(buffer) }
+    unsafe { std::ffi::CString::from_vec_unchecked(buffer) }
+}
+
+/// Decodes `%XX` sequences. Malformed sequences are left as is.
+pub(crate) fn percent_decode(text: &str) -> String {
+    let bytes = text.as_bytes();
+    let mut result = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+
+    while i < bytes.len() {
+        let hex = bytes.get(i + 1..i + 3)
+            .and_then(|hex| std::str::from_utf8(hex).ok())
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+
+        match (bytes[i], hex) {
+            (b'%', Some(byte)) => {
+                result.push(byte);
+                i += 3;
+            }
+            (byte, _) => {
+                result.push(byte);
+                i += 1;
+            }
+        }
+    }
+
+    String::from_utf8_lossy(&result).into_owned()
+}
+
+/// Returns `(major, minor)` version of current OpenGL context
+#[cfg(feature = "gl")]
+pub(crate) fn gl_version() -> (i32, i32) {
+    let (mut major, mut minor) = (0, 0);
+    unsafe {
+        gl::GetIntegerv(gl::MAJOR_VERSION, &mut major);
+        gl::GetIntegerv(gl::MINOR_VERSION, &mut minor);
+    }
+    (major, minor)
+}
+
+#[cfg(feature = "gl")]
+pub(crate) fn gl_has_extension(name: &str) -> bool {
+    let mut count: gl::types::GLint = 0;
+    unsafe {
+        gl::GetIntegerv(gl::NUM_EXTENSIONS, &mut count);
+    }
+
+    (0..count.max(0) as u32).any(|i| {
+        let extension = unsafe { gl::GetStringi(gl::EXTENSIONS, i) };
+        !extension.is_null() && unsafe { std::ffi::CStr::from_ptr(extension as *const _) }.to_bytes() == name.as_bytes()
+    })
 }
 
+/// Path with optional `protocol://` prefix. Components are split by both `/` and `\`, `.` and `..` are resolved
+/// (relative paths can start with `..`).
+/// 
+/// Protocols are case-insensitive (like URL schemes), so they are stored lowercase:
+/// `FILE://a/b`, `file://a/b` and `file://a/b/` are equal.
+/// 
+/// Everything after `?` is a query (like `gen://noise?octaves=4`), it is not split into components.
+/// 
+/// Trailing separator is remembered as a hint, that the path points to a directory (see `is_dir_hint`),
+/// but it does not affect comparison or display.
 #[derive(Debug, Clone)]
 pub struct Path {
     protocol: Option<String>,
     components: Vec<String>,
+    query: Option<String>,
+    dir_hint: bool,
 }
 
 impl Path {
     pub fn new(from: &str) -> Self {
+        Self::with_separators(from, &['/', '\\'])
+    }
+
+    /// Same as `new`, but components are split by `separators` instead of `/` and `\`.
+    /// Useful for resource names like `com.example.shader`. The path is still displayed with `/`.
+    pub fn with_separators(from: &str, separators: &[char]) -> Self {
         let (protocol, path) = get_protocol_and_path(from);
-        let components = path.split(|c| c == '\\' || c == '/')
-            .filter(|component| component.len() > 0 && component != &".");
-    
-        let mut final_components = vec![];
-    
-        for component in components {
-            if component == ".." {
-                let _ = final_components.pop();
-            } else {
-                final_components.push(component.to_string());
-            }
-        }
+        let (path, query) = match path.split_once('?') {
+            Some((path, query)) => (path, Some(query.to_owned())),
+            None => (path, None),
+        };
+        let rooted = is_filesystem(protocol) && path.starts_with(separators);
+        let dir_hint = path.ends_with(separators);
+        let components = path.split(separators).map(|component| component.to_owned());
     
         Path { 
-            protocol: protocol.map(|str| str.to_owned()), 
-            components: final_components 
+            protocol: protocol.map(|str| str.to_lowercase()), 
+            components: normalize(components, protocol.is_some(), rooted),
+            query,
+            dir_hint,
         }
     }
 
+    /// Appends `path` to this one. If `path` has a protocol (is absolute), it replaces this path entirely,
+    /// just like `std::path::Path::join` does. If `path` starts with `/`, it is taken from the root of this path's protocol.
     pub fn join(&self, path: impl Into<Path>) -> Path {
         let path: Path = path.into();
-        assert!(path.protocol.is_none());
+        if path.protocol.is_some() {
+            return path;
+        }
 
-        let mut result = self.clone();
-        result.components.extend(path.components);
-        result
+        let (base, rooted) = match path.is_rooted() {
+            true => (vec![], is_filesystem(self.protocol.as_deref())),
+            false => (self.components.clone(), self.is_rooted()),
+        };
+
+        Path {
+            protocol: self.protocol.clone(),
+            components: normalize(base.into_iter().chain(path.components), self.protocol.is_some(), rooted),
+            query: path.query,
+            dir_hint: path.dir_hint,
+        }
     }
 
+    /// Returns the path with `.` and `..` collapsed. Paths are already normalized on construction and `join`,
+    /// so it is the same path - the method is an explicit (and cheap) way to make sure of it.
+    ///
+    /// ```rust
+    /// use shader_loader::Path;
+    ///
+    /// assert_eq!(Path::new("a/b/../c").normalize().to_string(), "a/c");
+    /// assert_eq!(Path::new("./a/./b").normalize().to_string(), "a/b");
+    /// assert_eq!(Path::new("../a").normalize().to_string(), "../a");
+    /// ```
+    pub fn normalize(&self) -> Path {
+        let rooted = self.is_rooted();
+        Path {
+            components: normalize(self.components.iter().cloned(), self.protocol.is_some(), rooted),
+            ..self.clone()
+        }
+    }
+
+    /// Whether the path starts from filesystem root, like `/usr/share` or `file:///usr/share`
+    fn is_rooted(&self) -> bool {
+        self.components.first().is_some_and(|component| component.is_empty())
+    }
+
+    fn joined_components(&self) -> String {
+        match self.components.as_slice() {
+            [root] if root.is_empty() => "/".to_owned(),
+            components => components.join("/"),
+        }
+    }
+
+    /// Joins all `parts` one after another, like calling `join` for each of them
+    pub fn join_all(&self, parts: &[&str]) -> Path {
+        parts.iter().fold(self.clone(), |path, part| path.join(*part))
+    }
+
+    /// Builds path from `parts`, e.g. `Path::from_components(Some("res"), &["a", "b"])` is `res://a/b`
+    pub fn from_components(protocol: Option<&str>, parts: &[&str]) -> Path {
+        let root = Path { protocol: protocol.map(|protocol| protocol.to_lowercase()), components: vec![], query: None, dir_hint: false };
+        root.join_all(parts)
+    }
+
+    /// Same as `join`, but fails if `path` has a protocol instead of replacing this path.
+    pub fn try_join(&self, path: impl Into<Path>) -> Result<Path, String> {
+        let path: Path = path.into();
+        match &path.protocol {
+            Some(_) => Err(format!("Cannot join absolute path {path} onto {self}")),
+            None => Ok(self.join(path)),
+        }
+    }
+
+    /// Removes the last component. Query belongs to it, so it is removed too
     pub fn pop(&mut self) -> Option<String> {
+        self.query = None;
         self.components.pop()
     }
 
+    pub fn query(&self) -> Option<&str> {
+        self.query.as_deref()
+    }
+
+    /// Whether the path was written with trailing separator (like `shaders/`), so it probably means a directory.
+    /// Protocols can use it to tell directory requests apart.
+    ///
+    /// ```rust
+    /// use shader_loader::Path;
+    ///
+    /// assert!(Path::new("res://shaders/").is_dir_hint());
+    /// assert!(!Path::new("res://shaders").is_dir_hint());
+    /// assert!(Path::new("res://").join("lib/").is_dir_hint());
+    /// assert_eq!(Path::new("res://shaders/"), Path::new("res://shaders"));
+    /// ```
+    pub fn is_dir_hint(&self) -> bool {
+        self.dir_hint
+    }
+
+    /// Returns the path with percent-encoded characters (like `%20`) decoded.
+    /// Paths without protocol are not URLs, so they are returned as is.
+    pub fn decoded(&self) -> Path {
+        let mut result = self.clone();
+        if result.protocol.is_some() {
+            for component in result.components.iter_mut() {
+                *component = percent_decode(component);
+            }
+        }
+        result
+    }
+
+    /// Computes relative path from this path (treated as a directory) to `target`, like `../foo/bar`.
+    /// Returns `None` if paths have different protocols, only one of them is rooted, or this path
+    /// has `..` components left after the common part (names of the directories to go into are unknown then).
+    ///
+    /// ```
+    /// use shader_loader::Path;
+    ///
+    /// let relative = |from: &str, to: &str| Path::new(from).relative_to(&Path::new(to)).map(|path| path.to_string());
+    ///
+    /// assert_eq!(relative("res://shaders/a", "res://shaders/b/c.glsl").as_deref(), Some("../b/c.glsl"));
+    /// assert_eq!(relative("/a", "/a/b/c").as_deref(), Some("b/c"));
+    /// assert_eq!(relative("a/b", "a").as_deref(), Some(".."));
+    /// assert_eq!(relative("../a", "../b").as_deref(), Some("../b"));
+    ///
+    /// assert_eq!(relative("res://a", "file://a"), None);
+    /// assert_eq!(relative("/a", "b"), None);
+    /// assert_eq!(relative("../a", "b"), None);
+    /// ```
+    pub fn relative_to(&self, target: &Path) -> Option<Path> {
+        if self.protocol != target.protocol || self.is_rooted() != target.is_rooted() {
+            return None;
+        }
+
+        let common = self.components.iter()
+            .zip(target.components.iter())
+            .take_while(|(a, b)| a == b)
+            .count();
+
+        if self.components[common..].iter().any(|component| component == "..") {
+            return None;
+        }
+
+        let mut components = vec!["..".to_owned(); self.components.len() - common];
+        components.extend(target.components[common..].iter().cloned());
+
+        Some(Path { protocol: None, components, query: target.query.clone(), dir_hint: target.dir_hint })
+    }
+
+    /// Checks whether `prefix` is made of first components of this path. Paths with different protocols never match.
+    pub fn starts_with(&self, prefix: &Path) -> bool {
+        self.protocol == prefix.protocol && self.components.starts_with(&prefix.components)
+    }
+
+    /// Returns the rest of the path after `prefix` (without protocol), or `None` if it does not start with `prefix`
+    pub fn strip_prefix(&self, prefix: &Path) -> Option<Path> {
+        if !self.starts_with(prefix) {
+            return None;
+        }
+
+        Some(Path {
+            protocol: None,
+            components: self.components[prefix.components.len()..].to_vec(),
+            query: self.query.clone(),
+            dir_hint: self.dir_hint,
+        })
+    }
+
     pub fn dirname(&self) -> Path {
         let mut result = self.clone();
         result.pop();
         result
     }
+
+    /// Returns the string, which is the same for all equivalent paths, so it can be used as a key.
+    /// Unlike `Display`, it is always prefixed with protocol (`file` if there is none) and percent-decoded.
+    pub fn canonical_string(&self) -> String {
+        let protocol = self.protocol.as_deref().unwrap_or("file");
+        let query = self.query.as_ref().map(|query| format!("?{query}")).unwrap_or_default();
+        format!("{protocol}://{}{query}", self.decoded().joined_components())
+    }
+}
+
+fn is_filesystem(protocol: Option<&str>) -> bool {
+    protocol.is_none_or(|protocol| protocol.eq_ignore_ascii_case("file"))
+}
+
+/// Removes empty and `.` components and resolves `..`. Leading `..` are kept for relative paths,
+/// but dropped for paths with protocol or `rooted` ones, as there is nothing above their root.
+/// Root of `rooted` paths is kept as an empty first component, so they are displayed with leading `/`.
+fn normalize(components: impl Iterator<Item = String>, has_protocol: bool, rooted: bool) -> Vec<String> {
+    let mut result: Vec<String> = vec![];
+    let root = rooted as usize;
+    if rooted {
+        result.push("".to_owned());
+    }
+
+    for component in components {
+        match component.as_str() {
+            "" | "." => {}
+            ".." => match result[root..].last() {
+                Some(last) if last != ".." => {
+                    result.pop();
+                }
+                _ if !has_protocol && !rooted => result.push(component),
+                _ => {}
+            },
+            _ => result.push(component),
+        }
+    }
+
+    result
+}
+
+impl PartialEq for Path {
+    fn eq(&self, other: &Self) -> bool {
+        self.protocol == other.protocol && self.components == other.components && self.query == other.query
+    }
+}
+
+impl Eq for Path {}
+
+impl Hash for Path {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.protocol.hash(state);
+        self.components.hash(state);
+        self.query.hash(state);
+    }
 }
 
 impl Display for Path {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match &self.protocol {
-            None => write!(f, "{}", self.components.join("/")),
-            Some(protocol) => write!(f, "{protocol}://{}", self.components.join("/"))
+            None => write!(f, "{}", self.joined_components())?,
+            Some(protocol) => write!(f, "{protocol}://{}", self.joined_components())?
+        }
+        match &self.query {
+            None => Ok(()),
+            Some(query) => write!(f, "?{query}"),
         }
     }
 }
 
+/// Iterates over components (without protocol and query). Paths from filesystem root start with an empty component.
+///
+/// ```rust
+/// use shader_loader::Path;
+///
+/// let path = Path::new("res://a/b/c");
+/// assert_eq!(path.into_iter().collect::<Vec<_>>(), ["a", "b", "c"]);
+/// assert_eq!(&path[1], "b");
+/// ```
+impl<'a> IntoIterator for &'a Path {
+    type Item = &'a str;
+    type IntoIter = std::iter::Map<std::slice::Iter<'a, String>, fn(&'a String) -> &'a str>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.components.iter().map(String::as_str)
+    }
+}
+
+impl Index<usize> for Path {
+    type Output = str;
+
+    fn index(&self, index: usize) -> &str {
+        &self.components[index]
+    }
+}
+
 impl Into<Path> for &str {
     fn into(self) -> Path {
         Path::new(self)
@@ -79,4 +390,34 @@ impl Into<Path> for String {
     fn into(self) -> Path {
         Path::new(&self)
     }
-}
\ No newline at end of file
+}
+
+impl From<&String> for Path {
+    fn from(path: &String) -> Self {
+        Path::new(path)
+    }
+}
+
+impl From<&Path> for Path {
+    fn from(path: &Path) -> Self {
+        path.clone()
+    }
+}
+
+impl From<PathBuf> for Path {
+    fn from(path: PathBuf) -> Self {
+        Path::new(&path.to_string_lossy())
+    }
+}
+
+impl TryFrom<&Path> for PathBuf {
+    type Error = String;
+
+    /// Only paths without protocol (or with `file` protocol) can be converted into filesystem paths
+    fn try_from(path: &Path) -> Result<Self, Self::Error> {
+        match path.protocol.as_deref() {
+            None | Some("file") => Ok(PathBuf::from(path.joined_components())),
+            Some(protocol) => Err(format!("Path with protocol {protocol} cannot be converted into a filesystem path ({path})")),
+        }
+    }
+}
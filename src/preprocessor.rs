@@ -1,4 +1,4 @@
-use std::{rc::Rc, collections::HashSet};
+use std::{rc::Rc, borrow::Cow, cell::RefCell, collections::{HashSet, HashMap}, ops::Range};
 
 use regex::Regex;
 
@@ -8,10 +8,16 @@ pub struct Segment {
     pub start_line: usize,
     pub end_line: usize,
     pub original_file: Rc<String>,  // Just not to clone too many text
+    pub replaced_lines: usize, // How many lines of parent segment this one replaced (1 for include directive, 0 for inserted text)
+    pub first_line: usize, // Line of `original_file` the segment starts at (non-zero only for lines moved out of their place)
+    pub depth: usize, // How many segments contain this one (0 for the root file)
+    pub included: bool, // Whether it is a file included by the loader (see `replace_line_with_includes`)
 }
 
 impl Segment {
+    /// Empty segments (left by removed lines) cannot contain anything
     pub fn is_inside(&self, of: &Self) -> bool {
+        of.start_line < of.end_line &&
         self.start_line >= of.start_line && 
         self.end_line <= of.end_line
     }
@@ -37,14 +43,123 @@ impl FileIncludes {
             segments: vec![Segment {
                 start_line: 0,
                 end_line,
-                original_file: Rc::new(original_file)
+                original_file: Rc::new(original_file),
+                replaced_lines: 0,
+                first_line: 0,
+                depth: 0,
+                included: false,
             }]
         }
     }
 
+    /// Stitches several units together, putting lines of `separator` between them (nothing, if it is empty).
+    /// Lines of each unit keep their mapping, separator lines belong to `<concat>` file.
+    pub fn concat(units: Vec<FileIncludes>, separator: &str) -> FileIncludes {
+        let separator: Vec<String> = match separator {
+            "" => vec![],
+            _ => separator.split('\n').map(|s| s.to_owned()).collect(),
+        };
+
+        let mut lines: Vec<String> = vec![];
+        let mut segments = vec![Segment {
+            start_line: 0,
+            end_line: 0,
+            original_file: Rc::new("<concat>".to_owned()),
+            replaced_lines: 0,
+            first_line: 0,
+            depth: 0,
+            included: false,
+        }];
+
+        for (i, unit) in units.into_iter().enumerate() {
+            if i != 0 {
+                lines.extend(separator.iter().cloned());
+            }
+
+            let offset = lines.len();
+            segments.extend(unit.segments.into_iter().map(|mut segment| {
+                segment.start_line += offset;
+                segment.end_line += offset;
+                segment.depth += 1;
+                segment
+            }));
+            lines.extend(unit.lines);
+        }
+
+        segments[0].end_line = lines.len();
+        FileIncludes { lines, segments }
+    }
+
     pub fn text(&self) -> String {
         self.lines.join("\n")
-    } 
+    }
+
+    /// Lines `[start, end)` of the text joined, e.g. to show a snippet around an error. Out of range bounds are clamped.
+    pub fn text_range(&self, start: usize, end: usize) -> String {
+        let end = end.min(self.lines.len());
+        let start = start.min(end);
+        self.lines[start..end].join("\n")
+    }
+
+    /// Text, where region of each included file is wrapped in `#ifndef __FILE_<hash> / #define __FILE_<hash> / #endif`
+    /// guard, so the flattened shader can be pasted into another include system. Hash is computed from file name.
+    /// The root file is not guarded, as `#version` has to stay its first line.
+    pub fn text_with_guards(&self) -> String {
+        let mut openings: Vec<Vec<String>> = vec![vec![]; self.lines.len()];
+        let mut closings: Vec<usize> = vec![0; self.lines.len()];
+
+        for segment in self.segments.iter().skip(1) {
+            // Lines replaced via `replace_line_with` are not separate files
+            if !segment.included || segment.start_line == segment.end_line {
+                continue;
+            }
+
+            // FNV-1a, so guard names do not change between builds
+            let hash = segment.original_file.bytes()
+                .fold(0xcbf29ce484222325u64, |hash, byte| (hash ^ byte as u64).wrapping_mul(0x100000001b3));
+            openings[segment.start_line].push(format!("#ifndef __FILE_{hash:016X}\n#define __FILE_{hash:016X}"));
+            closings[segment.end_line - 1] += 1;
+        }
+
+        let mut result: Vec<String> = vec![];
+        for (line_id, line) in self.lines.iter().enumerate() {
+            result.append(&mut openings[line_id]);
+            result.push(line.clone());
+            result.extend(std::iter::repeat_n("#endif".to_owned(), closings[line_id]));
+        }
+
+        result.join("\n")
+    }
+
+    /// Returns a warning if there is no `main(` outside of comments. Such stage links, but does nothing -
+    /// usually it means that the file with the entry point was not included.
+    pub fn entry_point_warning(&self) -> Option<String> {
+        lazy_static::lazy_static! {
+            static ref MAIN_REGEX: Regex = Regex::new(r#"\bmain\s*\("#).unwrap();
+        }
+
+        match strip_comments(&self.lines).iter().any(|line| MAIN_REGEX.is_match(line)) {
+            true => None,
+            false => Some(format!("File {} has no entry point (`main` function)", self.segments[0].original_file)),
+        }
+    }
+
+    /// Text without comments, indentation and blank lines. Meant for release builds: line numbers change,
+    /// so errors of shader compiled from it can not be mapped back to original files.
+    ///
+    /// ```
+    /// use shader_loader::preprocessor::FileIncludes;
+    ///
+    /// let file = FileIncludes::new("  float/**/x; // unused\n\n/* multi\n line */ void main() {}", "main.glsl".to_owned());
+    /// assert_eq!(file.minified_text(), "float x;\nvoid main() {}");
+    /// ```
+    pub fn minified_text(&self) -> String {
+        strip_comments(&self.lines).iter()
+            .map(|line| line.trim())
+            .filter(|line| !line.is_empty())
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
 
     /// Returns the segment of this line
     pub fn last_segment_at(&self, line: usize) -> Option<Segment> {
@@ -57,36 +172,63 @@ impl FileIncludes {
         None
     }
 
+    /// Maps line of the text back to the file it came from and the line in that file (both 0-based).
+    /// 
+    /// ```rust
+    /// use shader_loader::preprocessor::{FileLoader, EmbeddedFs};
+    /// let files = [
+    ///     ("a.glsl", "a0\n#include_once \"b.glsl\"\na2\na3"),
+    ///     ("b.glsl", "b0\nb1\n#include_once \"c.glsl\"\nb3"),
+    ///     ("c.glsl", "#include_once \"d.glsl\"\nc1\nc2"),
+    ///     ("d.glsl", "d0\nd1"),
+    /// ];
+    /// let mut loader = FileLoader::new();
+    /// loader.add_protocol("mem".to_owned(), EmbeddedFs::from_files(&files).into_protocol()).unwrap();
+    /// let file = loader.load_file("mem://a.glsl").unwrap();
+    /// 
+    /// // Every line maps back to the same line of the file it came from
+    /// for (line_id, line) in file.text().lines().enumerate() {
+    ///     let (original_file, original_line) = file.file_and_line_at(line_id).unwrap();
+    ///     let (_, source) = files.iter().find(|(name, _)| format!("mem://{name}") == *original_file).unwrap();
+    ///     assert_eq!(source.lines().nth(original_line), Some(line));
+    /// }
+    /// 
+    /// // Lines after 3 levels of nested includes
+    /// assert_eq!(file.file_and_line_at(7), Some(("mem://b.glsl".to_owned().into(), 3)));
+    /// assert_eq!(file.file_and_line_at(9), Some(("mem://a.glsl".to_owned().into(), 3)));
+    /// assert_eq!(file.file_and_line_at(10), None);
+    /// ```
     pub fn file_and_line_at(&self, line: usize) -> Option<(Rc<String>, usize)> {
-        let segment = match self.last_segment_at(line) {
-            None => return None,
-            Some(s) => s,
-        };
+        let segment_id = self.segments.iter()
+            .rposition(|segment| line >= segment.start_line && line < segment.end_line)?;
+        let segment = &self.segments[segment_id];
 
-        let mut local_line = line - segment.start_line;
+        let mut local_line = line - segment.start_line + segment.first_line;
 
-        for seg in self.segments.iter() {
-            if self.get_segment_parent(seg.clone()) == Some(segment.clone()) {
-                local_line -= seg.end_line - seg.start_line - 1;
+        // Only children that are fully above the line shift it
+        for (child_id, child) in self.segments.iter().enumerate().skip(segment_id + 1) {
+            if child.end_line <= line && self.parent_id(child_id) == Some(segment_id) {
+                local_line = local_line + child.replaced_lines - (child.end_line - child.start_line);
             }
         }
 
-        Some((segment.original_file, local_line))
+        Some((segment.original_file.clone(), local_line))
     }
 
-    pub fn get_segment_parent(&self, segment: Segment) -> Option<Segment> {
-        let pos = match self.segments.iter().position(|s| s.eq(&segment)) {
-            None => return None,
-            Some(pos) => pos,
-        };
-
-        for potential_parent in self.segments[..pos].iter().rev() {
-            if segment.is_inside(potential_parent) {
-                return Some(potential_parent.clone());
-            }
-        }
+    /// Index of the parent of segment `id`: the closest segment before it, which is one level higher and contains it.
+    /// Unlike `Segment::is_inside`, an empty segment can be the parent of an empty segment at the same place.
+    fn parent_id(&self, id: usize) -> Option<usize> {
+        let segment = &self.segments[id];
+        self.segments[..id].iter().rposition(|parent| {
+            parent.depth + 1 == segment.depth &&
+            parent.start_line <= segment.start_line &&
+            segment.end_line <= parent.end_line
+        })
+    }
 
-        None
+    pub fn get_segment_parent(&self, segment: Segment) -> Option<Segment> {
+        let pos = self.segments.iter().position(|s| s.eq(&segment))?;
+        self.parent_id(pos).map(|parent_id| self.segments[parent_id].clone())
     }
 
     pub fn all_segments_at(&self, line: usize) -> Vec<Segment> {
@@ -101,6 +243,97 @@ impl FileIncludes {
         vec
     } 
 
+    /// Returns all lines (with their indices in the final text) that came from `file`.
+    /// If the file is included in several places - lines from all the places are returned.
+    pub fn lines_from_file(&self, file: &str) -> Vec<(usize, &str)> {
+        self.lines.iter()
+            .enumerate()
+            .filter(|(line_id, _)| {
+                self.last_segment_at(*line_id)
+                    .is_some_and(|segment| segment.original_file.as_str() == file)
+            })
+            .map(|(line_id, line)| (line_id, line.as_str()))
+            .collect()
+    }
+
+    /// Checks that segments layout is consistent: first segment covers the whole text, no segment goes out of
+    /// the text, and every other segment lays inside a segment one level higher before it (children go after parents).
+    /// Mutating methods check it themselves in debug builds.
+    ///
+    /// ```
+    /// use shader_loader::preprocessor::{EmbeddedFs, FileLoader};
+    ///
+    /// let mut loader = FileLoader::new();
+    /// loader.add_protocol("mem".to_owned(), EmbeddedFs::from_files(&[
+    ///     ("main.glsl", "#version 330\n#include_once \"a.glsl\"\nvoid main() {}"),
+    ///     ("a.glsl", "float a;\nfloat a2;"),
+    /// ]).into_protocol()).unwrap();
+    ///
+    /// let mut file = loader.load_file("mem://main.glsl").unwrap();
+    /// file.replace_range_with(2..4, "float b;", "mem://b.glsl".to_owned().into());
+    /// file.insert_lines(1, "#define X", "mem://defines.glsl".to_owned().into());
+    /// file.delete_range(0..1);
+    ///
+    /// assert_eq!(file.text(), "#define X\nfloat a;\nfloat b;");
+    /// assert!(file.check_invariants().is_ok());
+    /// ```
+    pub fn check_invariants(&self) -> Result<(), String> {
+        let root = self.segments.first()
+            .ok_or("There are no segments")?;
+
+        if root.start_line != 0 || root.end_line != self.lines.len() {
+            return Err(format!(
+                "Root segment [{}, {}) does not cover all {} lines",
+                root.start_line, root.end_line, self.lines.len()
+            ));
+        }
+
+        for (i, segment) in self.segments.iter().enumerate() {
+            if segment.start_line > segment.end_line || segment.end_line > self.lines.len() {
+                return Err(format!(
+                    "Segment #{i} [{}, {}) of {} is out of text bounds ({} lines)",
+                    segment.start_line, segment.end_line, segment.original_file, self.lines.len()
+                ));
+            }
+
+            if i != 0 && self.parent_id(i).is_none() {
+                return Err(format!(
+                    "Segment #{i} [{}, {}) of {} has no parent before it",
+                    segment.start_line, segment.end_line, segment.original_file
+                ));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Renders every line of the text with the file and line it came from, followed by the table of segments.
+    /// Meant for debugging and bug reports.
+    pub fn debug_dump(&self) -> String {
+        let mut dump = "Lines:\n".to_owned();
+
+        for (line_id, line) in self.lines.iter().enumerate() {
+            let origin = match self.file_and_line_at(line_id) {
+                Some((file, original_line)) => format!("{file}:{original_line}"),
+                None => "<unknown>".to_owned(),
+            };
+            dump += &format!("{line_id:>5} | {origin} | {line}\n");
+        }
+
+        let mut segments: Vec<_> = self.segments.iter().collect();
+        segments.sort_by(|a, b| a.start_line.cmp(&b.start_line).then(b.end_line.cmp(&a.end_line)));
+
+        dump += "Segments:\n";
+        for segment in segments {
+            dump += &format!(
+                "    [{}, {}) {} (replaces {} lines)\n",
+                segment.start_line, segment.end_line, segment.original_file, segment.replaced_lines
+            );
+        }
+
+        dump
+    }
+
     pub fn all_used_files(&self) -> Vec<&str> {
         let mut map = HashSet::new();
 
@@ -112,33 +345,229 @@ impl FileIncludes {
     }
 
     pub fn replace_line_with(&mut self, line: usize, with: &str, original_file: Rc<String>) {
-        let insert_lines: Vec<_> = with.split("\n").map(|s| s.to_owned()).collect();
-        let new_lines_count = insert_lines.len();
-        
-        self.lines.remove(line);
-        for (i, new_line) in insert_lines.into_iter().enumerate() {
-            self.lines.insert(i + line, new_line);
+        self.replace_range_with(line..line + 1, with, original_file);
+    }
+
+    /// Replaces lines `range` with `with`, marking new lines as belonging to `original_file`.
+    /// Segments inside the range are dropped, segments crossing its borders are cut.
+    /// An empty range just inserts the text (see `insert_lines`).
+    pub fn replace_range_with(&mut self, range: Range<usize>, with: &str, original_file: Rc<String>) {
+        let new_lines = with.split('\n').map(|s| s.to_owned()).collect();
+        match range.is_empty() {
+            true => self.insert_lines(range.start, with, original_file),
+            false => self.replace_range(range, new_lines, Some(original_file)),
+        }
+    }
+
+    /// Removes lines `range`, leaving an empty segment in their place, so lines after it are still mapped correctly
+    pub fn delete_range(&mut self, range: Range<usize>) {
+        if !range.is_empty() {
+            self.replace_range(range, vec![], None);
+        }
+    }
+
+    /// Replaces non-empty `range` with `new_lines`. Segment of new lines belongs to `original_file`,
+    /// or to the file of the innermost segment containing the range, if `None`.
+    fn replace_range(&mut self, range: Range<usize>, new_lines: Vec<String>, original_file: Option<Rc<String>>) {
+        let (start, end) = (range.start, range.end);
+        let new_end = start + new_lines.len();
+        let shift = |line: usize| line - end + new_end;
+        let contains = |segment: &Segment| segment.start_line <= start && segment.end_line >= end;
+        let inside = |segment: &Segment| {
+            let on_border = segment.start_line == segment.end_line && (segment.start_line == start || segment.start_line == end);
+            !contains(segment) && !on_border && segment.start_line >= start && segment.end_line <= end
+        };
+
+        // Children go after parents, so a segment is dropped together with its parent (even if it is on the border)
+        let mut removed: Vec<bool> = vec![];
+        for (id, segment) in self.segments.iter().enumerate() {
+            removed.push(inside(segment) || self.parent_id(id).is_some_and(|parent_id| removed[parent_id]));
+        }
+
+        // Lines of segment's file covered by the range: its own lines there, plus the lines its removed children have replaced
+        let covered_lines = |segment_id: usize| {
+            let segment = &self.segments[segment_id];
+            let mut lines = segment.end_line.min(end).saturating_sub(segment.start_line.max(start));
+            for (child_id, child) in self.segments.iter().enumerate().skip(segment_id + 1) {
+                if self.parent_id(child_id) != Some(segment_id) {
+                    continue;
+                }
+                lines -= child.end_line.min(end).saturating_sub(child.start_line.max(start));
+                if removed[child_id] {
+                    lines += child.replaced_lines;
+                }
+            }
+            lines
+        };
+
+        let parent_id = self.segments.iter().rposition(contains).unwrap();
+        let parent = self.segments[parent_id].clone();
+        let replaced_lines = covered_lines(parent_id);
+
+        // Segments, which start inside the range, but end after it, continue from a later line of their file
+        let cut_lines: Vec<usize> = self.segments.iter().enumerate()
+            .map(|(id, segment)| match !contains(segment) && segment.start_line >= start && segment.start_line < end && segment.end_line > end {
+                true => covered_lines(id),
+                false => 0,
+            })
+            .collect();
+        for (segment, cut_lines) in self.segments.iter_mut().zip(cut_lines) {
+            segment.first_line += cut_lines;
+        }
+
+        self.lines.splice(start..end, new_lines);
+        let mut removed = removed.into_iter();
+        self.segments.retain(|_| !removed.next().unwrap());
+        let parent_id = self.segments.iter().rposition(contains).unwrap();
+
+        for segment in self.segments.iter_mut() {
+            if contains(segment) {
+                segment.end_line = shift(segment.end_line);
+            } else if segment.start_line >= end {
+                segment.start_line = shift(segment.start_line);
+                segment.end_line = shift(segment.end_line);
+            } else if segment.start_line < start && segment.end_line > start {
+                segment.end_line = start;
+            } else if segment.end_line > end {
+                segment.start_line = new_end;
+                segment.end_line = shift(segment.end_line);
+            }
         }
 
+        let segment = Segment {
+            start_line: start,
+            end_line: new_end,
+            original_file: original_file.unwrap_or(parent.original_file),
+            replaced_lines,
+            first_line: 0,
+            depth: parent.depth + 1,
+            included: false,
+        };
+
+        // Empty segment can be taken for a child of its neighbours, so it goes right after its parent
+        match new_end == start {
+            true => self.segments.insert(parent_id + 1, segment),
+            false => self.segments.push(segment),
+        }
+        debug_assert!(self.check_invariants().is_ok(), "{:?}", self.check_invariants());
+    }
+
+    /// Removes the line of root file, leaving an empty segment of `original_file` in its place,
+    /// so lines after it are still mapped correctly
+    fn remove_line(&mut self, line: usize, original_file: Rc<String>) {
+        self.lines.remove(line);
+
         for segment in self.segments.iter_mut() {
             if segment.start_line > line {
-                segment.start_line += new_lines_count;
                 segment.start_line -= 1;
             }
             if segment.end_line > line {
-                segment.end_line += new_lines_count;
                 segment.end_line -= 1;
             }
         }
 
-        self.segments.push(Segment { 
-            start_line: line, 
-            end_line: line + new_lines_count, 
-            original_file, 
+        // Right after the root, so root is found as its parent
+        self.segments.insert(1, Segment {
+            start_line: line,
+            end_line: line,
+            original_file,
+            replaced_lines: 1,
+            first_line: 0,
+            depth: 1,
+            included: false,
         });
+        debug_assert!(self.check_invariants().is_ok(), "{:?}", self.check_invariants());
+    }
+
+    /// Inserts text before line `at` (`at` can be equal to lines count), marking inserted lines as belonging to `original_file`.
+    pub fn insert_lines(&mut self, at: usize, text: &str, original_file: Rc<String>) {
+        let insert_lines: Vec<_> = text.split('\n').map(|s| s.to_owned()).collect();
+        let new_lines_count = insert_lines.len();
+
+        self.lines.splice(at..at, insert_lines);
+
+        for (i, segment) in self.segments.iter_mut().enumerate() {
+            if segment.start_line >= at && i != 0 {
+                segment.start_line += new_lines_count;
+                segment.end_line += new_lines_count;
+            } else if segment.end_line > at || i == 0 {
+                segment.end_line += new_lines_count;
+            }
+        }
+
+        let mut segment = Segment {
+            start_line: at,
+            end_line: at + new_lines_count,
+            original_file,
+            replaced_lines: 0,
+            first_line: 0,
+            depth: 0,
+            included: false,
+        };
+        segment.depth = self.segments.iter().rev()
+            .find(|parent| segment.is_inside(parent))
+            .map_or(0, |parent| parent.depth + 1);
+        self.segments.push(segment);
+        debug_assert!(self.check_invariants().is_ok(), "{:?}", self.check_invariants());
+    }
+
+    /// Appends lines to the end of the text, marking them as belonging to `virtual_file`
+    pub fn append_source(&mut self, text: &str, virtual_file: &str) {
+        self.insert_lines(self.lines.len(), text, Rc::new(virtual_file.to_owned()));
+    }
+
+    /// Inserts text right after `#version` line, or at the very start if there is no `#version`
+    pub fn insert_after_version(&mut self, text: &str, original_file: Rc<String>) {
+        let at = self.version_line().map(|line| line + 1).unwrap_or(0);
+        self.insert_lines(at, text, original_file);
+    }
+
+    /// Returns index of the first `#version` line, if there is one. Lines inside comments are skipped
+    pub fn version_line(&self) -> Option<usize> {
+        strip_comments(&self.lines).iter()
+            .position(|line| line.trim_start().starts_with("#version"))
+    }
+
+    /// Parses the first `#version` line into version number and profile, like `(330, Some("core"))`
+    pub fn detected_version(&self) -> Option<(u32, Option<String>)> {
+        let line = &strip_comments(&self.lines)[self.version_line()?];
+        let mut words = line.split_whitespace().skip(1);
+
+        let version = words.next()?.parse().ok()?;
+        let profile = words.next().map(|word| word.to_owned());
+        Some((version, profile))
+    }
+
+    /// Moves all `#extension` lines right after `#version` (or to the very start, if there is no `#version`),
+    /// as GLSL requires them to go before any code. Repeated extensions are left only once.
+    /// Moved lines are still mapped to the files and lines they came from.
+    pub fn hoist_extensions(&mut self) {
+        let mut extensions: Vec<(String, Rc<String>, usize)> = vec![];
+
+        for (line_id, line) in strip_comments(&self.lines).iter().enumerate() {
+            if !line.trim_start().starts_with("#extension") {
+                continue;
+            }
+
+            // Left blank, so lines after it are still mapped correctly
+            self.lines[line_id] = "".to_owned();
+
+            let normalized = line.split_whitespace().collect::<Vec<_>>().join(" ");
+            if extensions.iter().all(|(text, _, _)| *text != normalized) {
+                let (file, original_line) = self.file_and_line_at(line_id).unwrap();
+                extensions.push((normalized, file, original_line));
+            }
+        }
+
+        let first = self.version_line().map(|line| line + 1).unwrap_or(0);
+        for (i, (text, file, original_line)) in extensions.into_iter().enumerate() {
+            self.insert_lines(first + i, &text, file);
+            self.segments.last_mut().unwrap().first_line = original_line;
+        }
     }
 
     pub fn replace_line_with_includes(&mut self, line: usize, includes: FileIncludes) {
+        let depth = self.last_segment_at(line).map_or(0, |parent| parent.depth + 1);
         self.lines.remove(line); // Remove the line
         let new_lines_count = includes.lines.len();
 
@@ -157,17 +586,197 @@ impl FileIncludes {
             }
         }
 
-        for mut new_segment in includes.segments.into_iter() {
+        for (i, mut new_segment) in includes.segments.into_iter().enumerate() {
+            if i == 0 {
+                new_segment.replaced_lines = 1;
+                new_segment.included = true;
+            }
             new_segment.start_line += line;
             new_segment.end_line += line;
+            new_segment.depth += depth;
 
             self.segments.push(new_segment);
         }
+        debug_assert!(self.check_invariants().is_ok(), "{:?}", self.check_invariants());
+    }
+}
+
+/// Line of OpenGL compilation log, with position mapped back to the original file (if log line had one)
+#[derive(Debug, Clone, PartialEq)]
+pub struct Diagnostic {
+    pub message: String,
+    pub file: Option<Rc<String>>,
+    pub line: Option<usize>, // 1-based, like in the log and in include errors
+    pub column: Option<usize>,
+    pub included_from: Vec<Rc<String>>, // Outermost file first
+}
+
+pub type ErrorFormatter = dyn Fn(&Diagnostic) -> String;
+
+/// Formats diagnostic as `File <includes history> | Line N | <message>`
+pub fn default_error_formatter(diagnostic: &Diagnostic) -> String {
+    let (file, line) = match (&diagnostic.file, diagnostic.line) {
+        (Some(file), Some(line)) => (file, line),
+        _ => return diagnostic.message.clone(),
+    };
+
+    let mut filepath = "File ".to_owned();
+    for parent in diagnostic.included_from.iter() {
+        filepath += parent;
+        filepath += " included from\n";
     }
+    filepath += file;
+
+    format!("{filepath} | Line {line} | {}", diagnostic.message)
 }
 
 pub type Protocol = dyn Fn(&str) -> Result<String, String>;
 
+/// Converts path (without protocol) into the form, which is the same for all paths pointing to the same file.
+pub type Canonicalizer = dyn Fn(&str) -> Result<String, String>;
+
+/// Step of include resolution, reported to the trace handler (see `FileLoader::set_trace`)
+#[derive(Debug, Clone, PartialEq)]
+pub enum TraceEvent {
+    Loading(String),
+    Included { parent: String, child: String },
+    Suppressed { path: String, reason: String },
+    Failed { path: String, error: String },
+}
+
+pub type TraceHandler = dyn Fn(&TraceEvent);
+
+/// Configuration of [`FileLoader`], that is not related to protocols.
+/// 
+/// ```rust
+/// use shader_loader::preprocessor::{FileLoader, PreprocessOptions};
+/// let options = PreprocessOptions::new()
+///     .search_dir("shaders/lib")
+///     .error_formatter(|diagnostic| diagnostic.message.clone());
+/// 
+/// let loader = FileLoader::with_options(options);
+/// assert_eq!(loader.search_dirs().len(), 1);
+/// ```
+#[derive(Clone)]
+pub struct PreprocessOptions {
+    search_dirs: Vec<crate::Path>,
+    aliases: Vec<(String, crate::Path)>,
+    error_formatter: Rc<ErrorFormatter>,
+    trace: Option<Rc<TraceHandler>>,
+    strip_suppressed_lines: bool,
+    hoist_extensions: bool,
+    cache_expansions: bool,
+    max_total_includes: Option<usize>,
+}
+
+impl PreprocessOptions {
+    pub fn new() -> Self {
+        PreprocessOptions {
+            search_dirs: vec![],
+            aliases: vec![],
+            error_formatter: Rc::new(default_error_formatter),
+            trace: None,
+            strip_suppressed_lines: false,
+            hoist_extensions: false,
+            cache_expansions: false,
+            max_total_includes: None,
+        }
+    }
+
+    /// If set, `#include_once` lines of already included files are removed instead of being left blank
+    pub fn strip_suppressed_lines(mut self, strip: bool) -> Self {
+        self.strip_suppressed_lines = strip;
+        self
+    }
+
+    /// If set, all `#extension` lines of loaded file and its includes are moved right after `#version`
+    /// (see [`FileIncludes::hoist_extensions`])
+    pub fn hoist_extensions(mut self, hoist: bool) -> Self {
+        self.hoist_extensions = hoist;
+        self
+    }
+
+    /// If set, loader remembers result of `load_file` for each loaded file, so loading it again (e.g. with different
+    /// entry defines) does not expand its includes again. See [`FileLoader::clear_cache`]
+    ///
+    /// Only whole results of `load_file` are remembered: an include shared by two different files is still
+    /// expanded for each of them.
+    ///
+    /// ```
+    /// use std::{cell::Cell, rc::Rc};
+    /// use shader_loader::preprocessor::{EmbeddedFs, FileLoader, PreprocessOptions};
+    ///
+    /// let loads = Rc::new(Cell::new(0));
+    /// let counter = loads.clone();
+    /// let files = EmbeddedFs::from_files(&[
+    ///     ("main.glsl", "#version 330\n#include_once \"common.glsl\"\nvoid main() {}"),
+    ///     ("common.glsl", "float common;"),
+    /// ]);
+    ///
+    /// let mut loader = FileLoader::with_options(PreprocessOptions::new().cache_expansions(true));
+    /// loader.add_protocol("mem".to_owned(), move |path: &str| {
+    ///     counter.set(counter.get() + 1);
+    ///     files.get(path).map(str::to_owned).ok_or(format!("No file {path}"))
+    /// }).unwrap();
+    ///
+    /// let low = loader.load_file_with_entry_defines("mem://main.glsl", &[("QUALITY", "0")]).unwrap();
+    /// let high = loader.load_file_with_entry_defines("mem://main.glsl", &[("QUALITY", "2")]).unwrap();
+    /// assert_eq!(high.text(), "#version 330\n#define QUALITY 2\nfloat common;\nvoid main() {}");
+    /// assert_ne!(low.text(), high.text());
+    /// assert_eq!(loads.get(), 2); // `main.glsl` and `common.glsl`, once each
+    ///
+    /// loader.clear_cache();
+    /// loader.load_file("mem://main.glsl").unwrap();
+    /// assert_eq!(loads.get(), 4);
+    /// ```
+    pub fn cache_expansions(mut self, cache: bool) -> Self {
+        self.cache_expansions = cache;
+        self
+    }
+
+    /// Limits how many distinct files can be included into one loaded file. Protects from include explosions,
+    /// e.g. with generated protocols. There is no limit by default
+    pub fn max_total_includes(mut self, limit: Option<usize>) -> Self {
+        self.max_total_includes = limit;
+        self
+    }
+
+    /// Sets the function, which is called on every step of include resolution. Useful for debugging
+    pub fn trace<T>(mut self, handler: T) -> Self
+        where T: 'static + Fn(&TraceEvent)
+    {
+        self.trace = Some(Rc::new(handler));
+        self
+    }
+
+    /// Makes includes starting with `prefix` (like `@engine/lighting`) resolve against `target` instead
+    /// (like `mem://engine/lighting`). Aliases are checked in order they were added.
+    pub fn alias(mut self, prefix: &str, target: impl Into<crate::Path>) -> Self {
+        self.aliases.push((prefix.to_owned(), target.into()));
+        self
+    }
+
+    /// Adds a directory to look for included files in. Directories are searched in order they were added.
+    pub fn search_dir(mut self, dir: impl Into<crate::Path>) -> Self {
+        self.search_dirs.push(dir.into());
+        self
+    }
+
+    /// Sets the function, which formats each line of OpenGL compilation log
+    pub fn error_formatter<T>(mut self, formatter: T) -> Self
+        where T: 'static + Fn(&Diagnostic) -> String
+    {
+        self.error_formatter = Rc::new(formatter);
+        self
+    }
+}
+
+impl Default for PreprocessOptions {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 /// Loads files and unfolds `#include_once` preprocessor directives.
 /// 
 /// Also allows you to add your own protocols to load files from custom places. 
@@ -193,25 +802,214 @@ pub type Protocol = dyn Fn(&str) -> Result<String, String>;
 ///     }
 /// }
 /// ```
+/// 
+/// `#include_once <file>` is looked up only in search directories (see `add_search_dir`), while
+/// `#include_once "file"` is looked up relative to the including file first, and in search directories after.
+/// 
+/// Cloning a loader is cheap: protocols are shared, but protocols added to a clone do not affect the original.
+#[derive(Clone)]
 pub struct FileLoader {
-    protocols: Vec<(String, Box<Protocol>)>,
+    protocols: Vec<(String, Rc<Protocol>)>, // Rc, so cloned loaders can share protocols
+    canonicalizers: Vec<(String, Rc<Canonicalizer>)>,
+    options: PreprocessOptions,
+    expansions: RefCell<HashMap<String, FileIncludes>>, // Canonical key -> result of `load_file`
+    recorded_sources: RefCell<Option<HashMap<String, String>>>, // Texts loaded during `load_file_with_sources`
 }
 
 fn load_file(path: &str) -> Result<String, String> {
     let pathbuf = std::fs::canonicalize(path)
-        .map_err(|err| format!("Path error {path}: {}", err.to_string()))?;
+        .map_err(|err| io_error(path, err))?;
 
     std::fs::read_to_string(pathbuf)
-        .map_err(|err| format!("File loading error (file {path}): {}", err.to_string()))
+        .map_err(|err| io_error(path, err))
+}
+
+/// Describes missing and inaccessible files differently, so it is clear which one happened
+fn io_error(path: &str, err: std::io::Error) -> String {
+    match err.kind() {
+        std::io::ErrorKind::NotFound => format!("File {path} is not found"),
+        std::io::ErrorKind::PermissionDenied => format!("Permission denied to read file {path}"),
+        _ => format!("File loading error (file {path}): {err}"),
+    }
+}
+
+#[cfg(feature = "net")]
+fn http_load(url: &str) -> Result<String, String> {
+    let response = ureq::get(url).call().map_err(|err| match err {
+        ureq::Error::Status(404, _) => format!("File {url} is not found"),
+        ureq::Error::Status(code, _) => format!("File loading error (file {url}): server responded with status {code}"),
+        ureq::Error::Transport(transport) => format!("Network error: {transport}"), // Already mentions the url
+    })?;
+
+    // `into_string` fails on responses over 10 MB
+    response.into_string()
+        .map_err(|err| format!("File loading error (file {url}): {err}"))
+}
+
+fn canonicalize_file(path: &str) -> Result<String, String> {
+    std::fs::canonicalize(path)
+        .map(|pathbuf| pathbuf.to_string_lossy().into_owned())
+        .map_err(|err| format!("Path error {path}: {err}"))
+}
+
+/// `file://` paths are URLs, so they can contain `%20` and such
+fn decode_file_url<'a>(protocol: Option<&str>, filepath: &'a str) -> Cow<'a, str> {
+    if protocol.is_some_and(|protocol| protocol.eq_ignore_ascii_case("file")) {
+        Cow::Owned(crate::percent_decode(filepath))
+    } else {
+        Cow::Borrowed(filepath)
+    }
+}
+
+/// Removes `//` and `/* */` comments from lines, keeping lines count the same. Block comment is replaced
+/// with a space, so it still separates tokens. Comment markers inside string literals are left as is.
+fn strip_comments(lines: &[String]) -> Vec<String> {
+    let mut in_block_comment = false;
+    let mut result = Vec::with_capacity(lines.len());
+
+    for line in lines {
+        let mut stripped = String::with_capacity(line.len());
+        let mut in_string = false;
+        let mut chars = line.chars().peekable();
+
+        while let Some(c) = chars.next() {
+            if in_block_comment {
+                if c == '*' && chars.peek() == Some(&'/') {
+                    chars.next();
+                    in_block_comment = false;
+                }
+                continue;
+            }
+
+            match (c, chars.peek()) {
+                ('"', _) => {
+                    in_string = !in_string;
+                    stripped.push(c);
+                }
+                ('/', Some('/')) if !in_string => break,
+                ('/', Some('*')) if !in_string => {
+                    chars.next();
+                    stripped.push(' ');
+                    in_block_comment = true;
+                }
+                _ => stripped.push(c),
+            }
+        }
+
+        result.push(stripped);
+    }
+
+    result
+}
+
+/// Adds info about the include directive which caused an error
+fn include_error(error: String, parent_file: &str, parent_line: usize, directive: &str) -> String {
+    format!("{error}\n    included from {parent_file} | Line {} | {directive}", parent_line + 1)
+}
+
+/// Returns the guard name, if the file starts with `#ifndef NAME` immediately followed by `#define NAME`
+fn include_guard(lines: &[String]) -> Option<String> {
+    let mut directives = lines.iter()
+        .map(|line| line.split_whitespace().collect::<Vec<_>>())
+        .filter(|words| !words.is_empty());
+
+    match (directives.next()?.as_slice(), directives.next()?.as_slice()) {
+        (["#ifndef", guard], ["#define", defined]) if guard == defined => Some(guard.to_string()),
+        _ => None,
+    }
 }
 
 impl FileLoader {
     pub fn new() -> Self {
+        Self::with_options(PreprocessOptions::new())
+    }
+
+    pub fn with_options(options: PreprocessOptions) -> Self {
         FileLoader { 
-            protocols: vec![("file".to_string(), Box::new(load_file))],
+            protocols: vec![("file".to_string(), Rc::new(load_file))],
+            canonicalizers: vec![("file".to_string(), Rc::new(canonicalize_file))],
+            options,
+            expansions: RefCell::new(HashMap::new()),
+            recorded_sources: RefCell::new(None),
+        }
+    }
+
+    pub fn options(&self) -> &PreprocessOptions {
+        &self.options
+    }
+
+    /// Sets the function, which formats each line of OpenGL compilation log of shaders loaded by this loader
+    pub fn set_error_formatter<T>(&mut self, formatter: T)
+        where T: 'static + Fn(&Diagnostic) -> String
+    {
+        self.options.error_formatter = Rc::new(formatter);
+    }
+
+    pub fn error_formatter(&self) -> &ErrorFormatter {
+        self.options.error_formatter.as_ref()
+    }
+
+    /// Sets the function, which is called on every step of include resolution. Useful for debugging
+    pub fn set_trace(&mut self, handler: Box<TraceHandler>) {
+        self.options.trace = Some(Rc::from(handler));
+    }
+
+    /// If set, `#include_once` lines of already included files are removed instead of being left blank
+    pub fn strip_suppressed_lines(&mut self, strip: bool) {
+        self.options.strip_suppressed_lines = strip;
+    }
+
+    /// If set, all `#extension` lines are moved right after `#version` (see [`FileIncludes::hoist_extensions`])
+    pub fn hoist_extensions(&mut self, hoist: bool) {
+        self.options.hoist_extensions = hoist;
+    }
+
+    /// See [`PreprocessOptions::max_total_includes`]
+    pub fn max_total_includes(&mut self, limit: Option<usize>) {
+        self.options.max_total_includes = limit;
+    }
+
+    /// See [`PreprocessOptions::cache_expansions`]
+    pub fn cache_expansions(&mut self, cache: bool) {
+        self.options.cache_expansions = cache;
+    }
+
+    /// Forgets cached expansions. Has to be called when files change, or the loader is reconfigured
+    pub fn clear_cache(&self) {
+        self.expansions.borrow_mut().clear();
+    }
+
+    fn trace(&self, event: impl FnOnce() -> TraceEvent) {
+        if let Some(handler) = &self.options.trace {
+            handler(&event());
         }
     }
 
+    /// Adds a directory to look for included files in. Directories are searched in order they were added.
+    pub fn add_search_dir(&mut self, dir: impl Into<crate::Path>) {
+        self.options.search_dirs.push(dir.into());
+    }
+
+    /// See [`PreprocessOptions::alias`]
+    pub fn add_alias(&mut self, prefix: &str, target: impl Into<crate::Path>) {
+        self.options.aliases.push((prefix.to_owned(), target.into()));
+    }
+
+    pub fn search_dirs(&self) -> &[crate::Path] {
+        &self.options.search_dirs
+    }
+
+    /// Adds `http` and `https` protocols, so files (and includes relative to them) can be loaded by URL.
+    /// Responses over 10 MB are rejected.
+    #[cfg(feature = "net")]
+    pub fn with_http(mut self) -> Self {
+        for scheme in ["http", "https"] {
+            self.protocols.retain(|(protocol, _)| protocol != scheme);
+            self.protocols.push((scheme.to_owned(), Rc::new(move |path: &str| http_load(&format!("{scheme}://{path}")))));
+        }
+        self
+    }
+
     pub fn add_protocol<T>(&mut self, protocol: String, loader: T) -> Result<(), &'static str>
         where T: 'static + Fn(&str) -> Result<String, String>
     {
@@ -221,68 +1019,293 @@ impl FileLoader {
             }
         }
 
-        self.protocols.push((protocol, Box::new(loader)));
+        self.protocols.push((protocol, Rc::new(loader)));
         Ok(())
     }
 
+    /// Sets the function used to find out whether two paths of the `protocol` point to the same file,
+    /// so `#include_once` is not fooled by different relative paths. Replaces previous canonicalizer, if any.
+    pub fn set_canonicalizer<T>(&mut self, protocol: &str, canonicalizer: T)
+        where T: 'static + Fn(&str) -> Result<String, String>
+    {
+        self.canonicalizers.retain(|(name, _)| name != protocol);
+        self.canonicalizers.push((protocol.to_owned(), Rc::new(canonicalizer)));
+    }
+
+    /// Returns the key files are deduplicated by. If protocol has no canonicalizer, or canonicalization fails,
+    /// the path is used as is.
+    pub fn canonical_key(&self, path: &str) -> String {
+        let (protocol, filepath) = get_protocol_and_path(path);
+        let filepath = decode_file_url(protocol, filepath);
+        let protocol = protocol.unwrap_or("file");
+
+        let canonicalizer = self.canonicalizers.iter()
+            .find(|(name, _)| name.eq_ignore_ascii_case(protocol))
+            .map(|(_, canonicalizer)| canonicalizer);
+
+        match canonicalizer.map(|canonicalizer| canonicalizer(&filepath)) {
+            Some(Ok(canonical)) => format!("{}://{canonical}", protocol.to_lowercase()),
+            _ => path.to_owned(),
+        }
+    }
+
     pub fn load_file(&self, path: &str) -> Result<FileIncludes, String> {
-        self.load_file_inner(path, &mut HashSet::new())
+        let key = self.options.cache_expansions.then(|| self.canonical_key(path));
+        if let Some(cached) = key.as_ref().and_then(|key| self.expansions.borrow().get(key).cloned()) {
+            return Ok(cached);
+        }
+
+        let includes = self.load_file_inner(path, &mut HashSet::new())?;
+        let includes = self.postprocess(includes);
+
+        if let Some(key) = key {
+            self.expansions.borrow_mut().insert(key, includes.clone());
+        }
+        Ok(includes)
+    }
+
+    /// Same as `load_file`, but also returns texts of all loaded files (by their paths, as in `all_used_files`),
+    /// exactly as they were expanded. Useful to detect changes of the sources later. The cache is not used.
+    ///
+    /// ```
+    /// use std::cell::Cell;
+    /// use shader_loader::preprocessor::FileLoader;
+    ///
+    /// // File changes on every read
+    /// let version = Cell::new(0);
+    /// let mut loader = FileLoader::new();
+    /// loader.add_protocol("mem".to_owned(), move |_: &str| {
+    ///     version.set(version.get() + 1);
+    ///     Ok(format!("float v{};", version.get()))
+    /// }).unwrap();
+    ///
+    /// let (includes, sources) = loader.load_file_with_sources("mem://main.glsl").unwrap();
+    /// assert_eq!(includes.text(), "float v1;");
+    /// assert_eq!(sources["mem://main.glsl"], "float v1;");
+    /// ```
+    pub fn load_file_with_sources(&self, path: &str) -> Result<(FileIncludes, HashMap<String, String>), String> {
+        *self.recorded_sources.borrow_mut() = Some(HashMap::new());
+        let result = self.load_file_inner(path, &mut HashSet::new());
+        let sources = self.recorded_sources.borrow_mut().take().unwrap_or_default();
+        result.map(|includes| (self.postprocess(includes), sources))
+    }
+
+    /// Lists all files `path` depends on (including itself), sorted. Useful for build systems, does not need GL.
+    pub fn dependencies(&self, path: &str) -> Result<Vec<String>, String> {
+        let mut files: Vec<String> = self.load_file(path)?
+            .all_used_files()
+            .into_iter()
+            .map(|file| file.to_owned())
+            .collect();
+
+        files.sort();
+        files.dedup();
+        Ok(files)
+    }
+
+    /// Loads file and returns JSON with expanded text and the file and line each line of it came from:
+    /// `{"text": "...", "sourcemap": [{"file": "a.glsl", "line": 0}, ...]}`. Lines are 0-based.
+    #[cfg(feature = "serde")]
+    pub fn preprocess_to_json(&self, path: &str) -> Result<String, String> {
+        let includes = self.load_file(path)?;
+
+        let sourcemap: Vec<_> = (0..includes.lines.len())
+            .map(|line_id| match includes.file_and_line_at(line_id) {
+                Some((file, line)) => serde_json::json!({ "file": file.as_str(), "line": line }),
+                None => serde_json::Value::Null,
+            })
+            .collect();
+
+        let json = serde_json::json!({ "text": includes.text(), "sourcemap": sourcemap });
+        Ok(json.to_string())
+    }
+
+    /// Finds files included by `path`, which also have manual `#ifndef NAME / #define NAME` guards.
+    /// Such guards are harmless, but redundant with `#include_once`. Returns a warning for each such file.
+    pub fn include_guard_warnings(&self, path: &str) -> Result<Vec<String>, String> {
+        let mut warnings = vec![];
+
+        for file in self.dependencies(path)? {
+            if file == path {
+                continue;
+            }
+
+            let lines: Vec<String> = self.basic_load_file(&file)?
+                .split('\n')
+                .map(|line| line.to_owned())
+                .collect();
+
+            if let Some(guard) = include_guard(&strip_comments(&lines)) {
+                warnings.push(format!("File {file} is included with #include_once, but also has a manual include guard {guard}"));
+            }
+        }
+
+        Ok(warnings)
+    }
+
+    /// Loads file and inserts `#define NAME VALUE` lines for each of `defines` right after `#version`.
+    /// Defines only affect this load; errors in them are reported as errors of `<defines>` file.
+    pub fn load_file_with_entry_defines(&self, path: &str, defines: &[(&str, &str)]) -> Result<FileIncludes, String> {
+        let mut includes = self.load_file(path)?;
+
+        if !defines.is_empty() {
+            let defines: Vec<_> = defines.iter()
+                .map(|(name, value)| format!("#define {name} {value}"))
+                .collect();
+            includes.insert_after_version(&defines.join("\n"), Rc::new("<defines>".to_owned()));
+        }
+
+        Ok(includes)
+    }
+
+    /// Unfolds includes in `source` as if it was loaded from `virtual_path`: relative includes are resolved
+    /// against its directory. `virtual_path` itself is never loaded.
+    pub fn preprocess_source(&self, source: &str, virtual_path: &str) -> Result<FileIncludes, String> {
+        let mut used_files = HashSet::new();
+        used_files.insert(self.canonical_key(virtual_path));
+        let includes = self.preprocess_inner(source, virtual_path, &mut used_files)?;
+        Ok(self.postprocess(includes))
+    }
+
+    /// Passes, which are done once over the whole unfolded text
+    fn postprocess(&self, mut includes: FileIncludes) -> FileIncludes {
+        if self.options.hoist_extensions {
+            includes.hoist_extensions();
+        }
+        includes
     }
 
     pub fn load_file_inner(&self, path: &str, used_files: &mut HashSet<String>) -> Result<FileIncludes, String> {
+        used_files.insert(self.canonical_key(path));
+        self.trace(|| TraceEvent::Loading(path.to_owned()));
+
+        let file = self.basic_load_file(path)
+            .inspect_err(|error| self.trace(|| TraceEvent::Failed { path: path.to_owned(), error: error.clone() }))?;
+        if let Some(sources) = self.recorded_sources.borrow_mut().as_mut() {
+            sources.insert(path.to_owned(), file.clone());
+        }
+        self.preprocess_inner(&file, path, used_files)
+    }
+
+    fn preprocess_inner(&self, source: &str, path: &str, used_files: &mut HashSet<String>) -> Result<FileIncludes, String> {
         lazy_static::lazy_static! {
-            static ref INCLUDE_REGEX: Regex =       Regex::new(r#"\s*(#(?:pragma)? ?include_once *[ <"](?P<filename>[^\n\r"<>]*)[>"\n\r]?)"#).unwrap();
+            // Quoted and angled filenames are taken exactly as written (can contain spaces), bare ones are trimmed.
+            // Any whitespace (or none before a delimiter) is allowed around the directive, but it has to start the line
+            static ref INCLUDE_REGEX: Regex =       Regex::new(r#"^\s*(#\s*(?:pragma\s+)?include_once(?:\s*"(?P<quoted>[^\n\r"]*)"|\s*<(?P<angled>[^\n\r<>]*)>|\s+(?P<bare>[^\n\r"<>]+)))"#).unwrap();
         }
 
         let dirname = crate::Path::new(path).dirname();
-        used_files.insert(path.to_owned());
-        let file = self.basic_load_file(path)?;
-        let mut includes = FileIncludes::new(&file, path.to_owned());
-        let mut jobs_to_replace: Vec<(usize, String)> = vec![];
+        let mut includes = FileIncludes::new(source, path.to_owned());
+        let mut jobs_to_replace: Vec<(usize, String, String)> = vec![];
 
 
-        for (line_id, line) in includes.lines.iter().enumerate() {
+        // Commented out includes must be skipped
+        for (line_id, line) in strip_comments(&includes.lines).iter().enumerate() {
             if let Some(cap) = INCLUDE_REGEX.captures(line) {
-                let filepath = cap.get(2).unwrap();
-                let filepath = &line[filepath.start()..filepath.end()];
-                
-                let filepath_owned;
-                if get_protocol_and_path(filepath).0.is_none() { // Relative path
-                    filepath_owned = dirname.join(filepath).to_string();
-                } else { // Absolute
-                    filepath_owned = filepath.to_owned();
-                }
-                
+                let (filepath, from_search_dirs_only) = match (cap.name("quoted"), cap.name("angled"), cap.name("bare")) {
+                    (Some(quoted), _, _) => (quoted.as_str(), false),
+                    (_, Some(angled), _) => (angled.as_str(), true),
+                    (_, _, bare) => (bare.unwrap().as_str().trim(), false),
+                };
+                let directive = cap.get(1).unwrap().as_str().trim().to_owned();
 
-                jobs_to_replace.push((line_id, filepath_owned));
+                let filepath_owned = self.resolve_include(&dirname, filepath, from_search_dirs_only, used_files)
+                    .map_err(|err| include_error(err, path, line_id, &directive))?;
+                jobs_to_replace.push((line_id, filepath_owned, directive));
             }
         }
 
-        let mut line_offset = 0;
-        for (line_id, filepath) in jobs_to_replace.into_iter() {
-            if used_files.contains(&filepath) { 
+        let mut line_offset: isize = 0;
+        for (line_id, filepath, directive) in jobs_to_replace.into_iter() {
+            let at = (line_id as isize + line_offset) as usize;
+            let key = self.canonical_key(&filepath);
+            if used_files.contains(&key) { 
                 // If file is already included - we just ignore
-                includes.lines[line_id + line_offset] = "".to_owned();
+                self.trace(|| TraceEvent::Suppressed { path: filepath.clone(), reason: "already included".to_owned() });
+
+                // Files can't be empty, so the last line is never removed
+                if self.options.strip_suppressed_lines && includes.lines.len() > 1 {
+                    includes.remove_line(at, Rc::new(filepath));
+                    line_offset -= 1;
+                } else {
+                    includes.lines[at] = "".to_owned();
+                }
             } else {
-                used_files.insert(filepath.clone());
-                let new_includes = self.load_file_inner(&filepath, used_files)?;
+                // The root file is in `used_files` too
+                if let Some(limit) = self.options.max_total_includes.filter(|limit| used_files.len() > *limit) {
+                    let error = format!("Too many included files, the limit is {limit}");
+                    return Err(include_error(error, path, line_id, &directive));
+                }
+
+                used_files.insert(key);
+                self.trace(|| TraceEvent::Included { parent: path.to_owned(), child: filepath.clone() });
+                let new_includes = self.load_file_inner(&filepath, used_files)
+                    .map_err(|err| include_error(err, path, line_id, &directive))?;
                 let offset = new_includes.lines.len() - 1;
-                includes.replace_line_with_includes(line_id + line_offset, new_includes);
-                line_offset += offset;
+                includes.replace_line_with_includes(at, new_includes);
+                line_offset += offset as isize;
             }
         }
 
         Ok(includes)
     }
 
+    /// Finds out full path of included file.
+    /// 
+    /// Paths with protocol are used as is. Relative paths are resolved against `dirname` of including file
+    /// (so they are loaded with the same protocol, e.g. `mem://dir/a` includes `b` as `mem://dir/b`),
+    /// then against search dirs. If `from_search_dirs_only` is set - only search dirs are used.
+    /// Aliases are applied before everything else.
+    fn resolve_include(&self, dirname: &crate::Path, filepath: &str, from_search_dirs_only: bool, used_files: &HashSet<String>) -> Result<String, String> {
+        if let Some(aliased) = self.apply_alias(filepath) {
+            return Ok(aliased);
+        }
+
+        if get_protocol_and_path(filepath).0.is_some() { // Absolute
+            return Ok(filepath.to_owned());
+        }
+
+        let exists = |path: &String| used_files.contains(&self.canonical_key(path)) || self.basic_load_file(path).is_ok();
+
+        let relative = dirname.join(filepath).to_string();
+        if !from_search_dirs_only && (self.options.search_dirs.is_empty() || exists(&relative)) {
+            return Ok(relative);
+        }
+
+        for dir in self.options.search_dirs.iter() {
+            let candidate = dir.join(filepath).to_string();
+            if exists(&candidate) {
+                return Ok(candidate);
+            }
+        }
+
+        if from_search_dirs_only {
+            Err(format!("File <{filepath}> is not found in search directories"))
+        } else {
+            Ok(relative)
+        }
+    }
+
+    /// Rewrites `filepath` if it starts with one of aliases. Alias has to be followed by a separator (or be the whole path)
+    fn apply_alias(&self, filepath: &str) -> Option<String> {
+        self.options.aliases.iter().find_map(|(prefix, target)| {
+            let rest = filepath.strip_prefix(prefix.as_str())?;
+            match rest.chars().next() {
+                None | Some('/') | Some('\\') => Some(target.join(rest.trim_start_matches(['/', '\\'])).to_string()),
+                _ => None,
+            }
+        })
+    }
+
     /// Just loads file as is. No proccessing
     pub fn basic_load_file(&self, path: &str) -> Result<String, String> {
         let (protocol, filepath) = get_protocol_and_path(path);
+        let filepath = decode_file_url(protocol, filepath);
         let protocol = protocol.unwrap_or("file");
         let protocol = self.get_protocol(protocol)
             .ok_or(format!("Unsupported protocol: {protocol} ({path})"))?;
 
-        let text = protocol(filepath)?;
+        let text = protocol(&filepath)?;
         if text.is_empty() {
             Err(format!("Empty files ({path}) are unsupported because of technical reasons, sorry :("))
         } else {
@@ -292,14 +1315,59 @@ impl FileLoader {
 
     pub fn get_protocol(&self, name: &str) -> Option<&Protocol> {
         for (p_name, protocol) in self.protocols.iter() {
-            if name == p_name {
-                return Some(protocol);
+            if name.eq_ignore_ascii_case(p_name) { // Protocols are case-insensitive, just like in `Path`
+                return Some(protocol.as_ref());
             }
         }
         None
     }
 }
 
+/// Set of files embedded into the binary (for example with `include_str!`), that can be used as a protocol.
+/// 
+/// ```rust
+/// use shader_loader::preprocessor::{FileLoader, EmbeddedFs};
+/// let embedded = EmbeddedFs::new()
+///     .with_file("core/noise.glsl", "float noise();")
+///     .with_file("core/main.glsl", "#include_once \"noise.glsl\"\nvoid main() {}");
+/// 
+/// let mut loader = FileLoader::new();
+/// loader.add_protocol("embedded".to_owned(), embedded.into_protocol()).unwrap();
+/// 
+/// let file = loader.load_file("embedded://core/main.glsl").unwrap();
+/// assert_eq!(file.text(), "float noise();\nvoid main() {}");
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct EmbeddedFs {
+    files: HashMap<String, &'static str>,
+}
+
+impl EmbeddedFs {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn from_files(files: &[(&'static str, &'static str)]) -> Self {
+        files.iter()
+            .fold(Self::new(), |fs, (path, text)| fs.with_file(path, text))
+    }
+
+    pub fn with_file(mut self, path: &str, text: &'static str) -> Self {
+        self.files.insert(crate::Path::new(path).to_string(), text);
+        self
+    }
+
+    pub fn get(&self, path: &str) -> Option<&'static str> {
+        self.files.get(&crate::Path::new(path).to_string()).copied()
+    }
+
+    pub fn into_protocol(self) -> impl Fn(&str) -> Result<String, String> {
+        move |path| self.get(path)
+            .map(|text| text.to_owned())
+            .ok_or(format!("Embedded file does not exist: {path}"))
+    }
+}
+
 impl Default for FileLoader {
     fn default() -> Self {
         Self::new()
@@ -319,4 +1387,38 @@ pub fn get_protocol_and_path(path: &str) -> (Option<&str>, &str) {
     } else {
         (None, path)
     }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn file() -> FileIncludes {
+        let mut file = FileIncludes::new("#version 330\nfloat a;\nfloat b;\nvoid main() {}", "main.glsl".to_owned());
+        file.replace_line_with(1, "float a1;\nfloat a2;", "a.glsl".to_owned().into());
+        file.replace_line_with(2, "float b;", "b.glsl".to_owned().into());
+        file
+    }
+
+    #[test]
+    fn corrupted_segments_are_detected() {
+        assert!(file().check_invariants().is_ok());
+
+        // b.glsl, included on the last line of a.glsl, goes past the end of a.glsl
+        let mut overlapping = file();
+        overlapping.segments[2].end_line += 1;
+        assert!(overlapping.check_invariants().is_err());
+
+        // Child before its parent
+        let mut out_of_order = file();
+        out_of_order.segments.swap(0, 1);
+        assert!(out_of_order.check_invariants().is_err());
+
+        let mut out_of_text = file();
+        out_of_text.segments[1].end_line = 10;
+        assert!(out_of_text.check_invariants().is_err());
+
+        let mut inverted = file();
+        inverted.segments[1].start_line = 4;
+        assert!(inverted.check_invariants().is_err());
+    }
+}
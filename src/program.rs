@@ -1,75 +1,331 @@
-use std::path::PathBuf;
+use std::{path::PathBuf, rc::Rc, ffi::CString, marker::PhantomData};
 
 use gl::types::GLenum;
 use regex::Regex;
 
-use crate::{create_whitespace_cstring, shader::Shader, preprocessor::{FileLoader, FileIncludes}};
+use crate::{create_whitespace_cstring, shader::{Shader, shader_type_name}, preprocessor::{FileLoader, FileIncludes, Diagnostic, ErrorFormatter, default_error_formatter}};
 
 
 pub trait Uniformable {
     unsafe fn set_uniform(self, location: i32);
+
+    /// Sets uniform of `program` without binding it (`glProgramUniform*`).
+    /// By default `program` is bound only for the time of `set_uniform`, and previous program is restored.
+    /// 
+    /// # Safety
+    /// Requires GL 4.1 or `GL_ARB_separate_shader_objects`, unless the default implementation is used.
+    unsafe fn set_program_uniform(self, program: gl::types::GLuint, location: i32) where Self: Sized {
+        set_uniform_with_bound_program(self, program, location);
+    }
+}
+
+/// Binds `program` only for the time of `set_uniform` and restores previously bound one
+unsafe fn set_uniform_with_bound_program<T: Uniformable>(value: T, program: gl::types::GLuint, location: i32) {
+    let mut previous = 0;
+    gl::GetIntegerv(gl::CURRENT_PROGRAM, &mut previous);
+    gl::UseProgram(program);
+    value.set_uniform(location);
+    gl::UseProgram(previous as gl::types::GLuint);
+}
+
+/// Whether `glProgramUniform*` is available (GL 4.1 or `GL_ARB_separate_shader_objects`).
+/// Probed once per thread, since GL context is bound to a thread.
+fn has_program_uniform() -> bool {
+    thread_local! {
+        static HAS_PROGRAM_UNIFORM: std::cell::OnceCell<bool> = const { std::cell::OnceCell::new() };
+    }
+    HAS_PROGRAM_UNIFORM.with(|supported| *supported.get_or_init(|| {
+        crate::gl_version() >= (4, 1) || crate::gl_has_extension("GL_ARB_separate_shader_objects")
+    }))
+}
+
+/// Set of uniforms, that can be uploaded at once via `Program::set_uniforms`.
+/// 
+/// ```rust,no_run
+/// use shader_loader::program::{Program, Uniforms};
+/// 
+/// struct Params {
+///     time: f32,
+///     resolution: (f32, f32),
+/// }
+/// 
+/// impl Uniforms for Params {
+///     fn apply(&self, program: &Program) {
+///         program.uniform("u_time", self.time);
+///         program.uniform("u_resolution", self.resolution);
+///     }
+/// }
+/// 
+/// # let program = Program::from_files_auto("shader").unwrap();
+/// program.set_uniforms(&Params { time: 1.0, resolution: (800.0, 600.0) });
+/// ```
+pub trait Uniforms {
+    fn apply(&self, program: &Program);
 }
 
 
 
-fn parse_opengl_errors(error: String, file: &FileIncludes) -> String {
+fn parse_opengl_errors(error: String, file: &FileIncludes, formatter: &ErrorFormatter) -> String {
     lazy_static::lazy_static! {
-        pub static ref ERROR_POS_REGEX: Regex = Regex::new(r#"(\d)+\((\d+)\) :"#).unwrap();
+        // Position formats of different vendors: NVIDIA `0(12) :`, Mesa `0:12(3): error:`, AMD/Intel `ERROR: 0:12:`
+        pub static ref ERROR_POS_REGEXES: [Regex; 3] = [
+            Regex::new(r#"\d+\((?P<line>\d+)\) :"#).unwrap(),
+            Regex::new(r#"\d+:(?P<line>\d+)\((?P<column>\d+)\):"#).unwrap(),
+            Regex::new(r#"(?:ERROR|WARNING): \d+:(?P<line>\d+):"#).unwrap(),
+        ];
     }
 
-    let lines = error.split("\n");
+    let lines = error.split('\n');
     let mut edited_lines = "".to_owned();
 
-    for line in lines.into_iter() {
-        let mut line_owned = line.to_owned();
-        if let Some(caps) = ERROR_POS_REGEX.captures(line) {
-            //let full_match = caps.get(0).unwrap();
-            let row_no = caps.get(2).unwrap();
+    for line in lines {
+        let mut diagnostic = Diagnostic {
+            message: line.to_owned(),
+            file: None,
+            line: None,
+            column: None,
+            included_from: vec![],
+        };
 
-            let row_no: usize = (&line[row_no.start()..row_no.end()]).parse().unwrap();
+        if let Some(caps) = ERROR_POS_REGEXES.iter().find_map(|regex| regex.captures(line)) {
+            // Drivers count lines from 1
+            let position = caps["line"].parse().ok()
+                .and_then(|row_no: usize| row_no.checked_sub(1))
+                .and_then(|row_id| Some((row_id, file.file_and_line_at(row_id)?)));
 
-            let (original_filepath, original_line) = file.file_and_line_at(row_no).unwrap();
-            let includes_history = file.all_segments_at(row_no);
-            
-            let mut filepath = "File ".to_owned();
-            for i in 0..(includes_history.len() - 1) {
-                filepath += &includes_history[i].original_file;
-                filepath += " included from\n";
-            }
-            filepath += &original_filepath;
+            match position {
+                // Some drivers report lines past the end of the text - such lines are left as they are
+                None => diagnostic.message = format!("{line} (line is out of the preprocessed text, so it is not mapped to the original file)"),
+                Some((row_id, (original_filepath, original_line))) => {
+                    let mut includes_history = file.all_segments_at(row_id);
+                    includes_history.pop();
 
-            line_owned.insert_str(0, &format!("{filepath} | Line {original_line} | "))
+                    diagnostic.file = Some(original_filepath);
+                    diagnostic.line = Some(original_line + 1);
+                    diagnostic.column = caps.name("column").and_then(|column| column.as_str().parse().ok());
+                    diagnostic.included_from = includes_history.into_iter()
+                        .map(|segment| segment.original_file)
+                        .collect();
+                }
+            }
         }
-        edited_lines.push_str(&line_owned);
-        edited_lines.push_str("\n");
+
+        edited_lines.push_str(&formatter(&diagnostic));
+        edited_lines.push('\n');
     }
     edited_lines
 }
 
 
 
-pub struct Program(gl::types::GLuint);
+/// Compiles preprocessed file, mapping errors back to original files
+pub(crate) fn compile_includes(content: &FileIncludes, shader_type: GLenum, formatter: &ErrorFormatter) -> Result<Shader, String> {
+    Shader::from_source_string(content.text(), shader_type)
+        .map_err(|error| format!(
+            "Failed to compile {} stage:\n{}",
+            shader_type_name(shader_type),
+            parse_opengl_errors(error, content, formatter)
+        ))
+}
+
+/// Number of values `glGetUniform*v` writes for uniform of `gl_type`
+fn uniform_components(gl_type: GLenum) -> usize {
+    match gl_type {
+        gl::FLOAT_VEC2 | gl::DOUBLE_VEC2 | gl::INT_VEC2 | gl::UNSIGNED_INT_VEC2 | gl::BOOL_VEC2 => 2,
+        gl::FLOAT_VEC3 | gl::DOUBLE_VEC3 | gl::INT_VEC3 | gl::UNSIGNED_INT_VEC3 | gl::BOOL_VEC3 => 3,
+        gl::FLOAT_VEC4 | gl::DOUBLE_VEC4 | gl::INT_VEC4 | gl::UNSIGNED_INT_VEC4 | gl::BOOL_VEC4 => 4,
+        gl::FLOAT_MAT2 | gl::DOUBLE_MAT2 => 4,
+        gl::FLOAT_MAT2x3 | gl::FLOAT_MAT3x2 | gl::DOUBLE_MAT2x3 | gl::DOUBLE_MAT3x2 => 6,
+        gl::FLOAT_MAT2x4 | gl::FLOAT_MAT4x2 | gl::DOUBLE_MAT2x4 | gl::DOUBLE_MAT4x2 => 8,
+        gl::FLOAT_MAT3 | gl::DOUBLE_MAT3 => 9,
+        gl::FLOAT_MAT3x4 | gl::FLOAT_MAT4x3 | gl::DOUBLE_MAT3x4 | gl::DOUBLE_MAT4x3 => 12,
+        gl::FLOAT_MAT4 | gl::DOUBLE_MAT4 => 16,
+        // Scalars and opaque types (samplers, images, atomic counters)
+        _ => 1,
+    }
+}
+
+/// Finds `#pragma stage <name>` line and returns the stage it declares
+fn stage_pragma(content: &FileIncludes) -> Option<GLenum> {
+    let stage = content.text()
+        .lines()
+        .map(|line| line.split_whitespace().collect::<Vec<_>>())
+        .find_map(|words| match words.as_slice() {
+            ["#pragma", "stage", stage] => Some(stage.to_string()),
+            _ => None,
+        })?;
+
+    match stage.as_str() {
+        "vertex" => Some(gl::VERTEX_SHADER),
+        "fragment" => Some(gl::FRAGMENT_SHADER),
+        "geometry" => Some(gl::GEOMETRY_SHADER),
+        "tess_control" => Some(gl::TESS_CONTROL_SHADER),
+        "tess_evaluation" => Some(gl::TESS_EVALUATION_SHADER),
+        "compute" => Some(gl::COMPUTE_SHADER),
+        _ => None,
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct AttributeInfo {
+    pub name: String,
+    pub gl_type: GLenum,
+    pub size: i32,
+    pub location: i32,
+}
+
+/// `(NAME, VALUE)` pairs, each of them becomes `#define NAME VALUE` line
+pub type Defines<'a> = [(&'a str, &'a str)];
+
+/// Cached uniform location, see `Program::location_handle`. `-1` means there is no such uniform
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Location(pub i32);
+
+/// Flat column-major `mat2`. Plain `[f32; 4]` is uploaded as `vec4`, so `mat2` needs a distinct type
+/// (flat `[f32; 9]` and `[f32; 16]` are uploaded as `mat3` and `mat4`)
+///
+/// ```rust,no_run
+/// use shader_loader::program::Mat2;
+/// # let program = shader_loader::program::Program::from_files_auto("shader").unwrap();
+/// # let view_projection = [0.0f32; 16];
+///
+/// program.uniform("u_view_projection", view_projection); // mat4, e.g. from `Mat4::to_cols_array()`
+/// program.uniform("u_rotation", Mat2([0.0, 1.0, -1.0, 0.0])); // mat2
+/// program.uniform("u_color", [1.0f32, 0.5, 0.0, 1.0]); // vec4
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Mat2(pub [f32; 4]);
+
+/// 8-bit color (like pixels of images), uploaded as normalized `vec4`
+///
+/// ```rust
+/// use shader_loader::program::RgbaU8;
+///
+/// assert_eq!(RgbaU8([255, 128, 0, 255]).normalized(), [1.0, 128.0 / 255.0, 0.0, 1.0]);
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct RgbaU8(pub [u8; 4]);
+
+impl RgbaU8 {
+    /// Channels mapped from `0..=255` to `0.0..=1.0`
+    pub fn normalized(self) -> [f32; 4] {
+        self.0.map(|channel| channel as f32 / 255.0)
+    }
+}
+
+/// Linked program object. It can only be used on the thread of its GL context, so it is neither `Send` nor `Sync`:
+/// 
+/// ```rust,compile_fail
+/// fn assert_send<T: Send>() {}
+/// assert_send::<shader_loader::program::Program>();
+/// ```
+pub struct Program(gl::types::GLuint, crate::NotSend);
 
 impl Program {
 
-    pub fn from_loader(loader: &FileLoader, files: &[(&str, gl::types::GLenum)]) -> Result<Program, String> {
+    /// Loads and compiles each file as a stage of the program. Stage can be `None`, then it is taken from
+    /// `#pragma stage <name>` line of the file (`vertex`, `fragment`, `geometry`, `tess_control`, `tess_evaluation`, `compute`).
+    ///
+    /// All stages are compiled even if some of them fail, so errors of every stage are reported at once:
+    ///
+    /// ```rust,no_run
+    /// use shader_loader::{program::Program, preprocessor::FileLoader};
+    ///
+    /// let loader = FileLoader::new();
+    /// match Program::from_loader(&loader, &[("shaders/main.vert", Some(gl::VERTEX_SHADER)), ("shaders/main.frag", None)]) {
+    ///     Ok(program) => program.use_program(),
+    ///     Err(errors) => eprintln!("{errors}"),
+    /// }
+    /// ```
+    pub fn from_loader<S>(loader: &FileLoader, files: &[(&str, S)]) -> Result<Program, String>
+        where S: Copy + Into<Option<GLenum>>
+    {
         let mut loaded_files: Vec<(FileIncludes, GLenum)> = vec![];
 
         for (filepath, shader_type) in files {
-            loaded_files.push((
-                loader.load_file(filepath)?,
-                *shader_type
-            ));
-        }
-
-        let shaders: Result<Vec<Shader>, String> = loaded_files.into_iter()
-            .map(|(content, shader_type)| {
-                let text = content.text();
-                let shader = Shader::from_source_string(text, shader_type)
-                    .map_err(|error| parse_opengl_errors(error, &content));
-                shader
-            }).collect();
-        let shaders = shaders?;
+            let content = loader.load_file(filepath)?;
+            let shader_type = match (*shader_type).into().or_else(|| stage_pragma(&content)) {
+                Some(shader_type) => shader_type,
+                None => return Err(format!("File {filepath} has neither stage specified, nor `#pragma stage` line")),
+            };
+            loaded_files.push((content, shader_type));
+        }
+
+        Self::compile_loaded(&loaded_files, loader.error_formatter())
+    }
+
+    /// Same as `from_loader`, but each file can have a prelude, which is inserted right after `#version` line
+    /// (or at the very start, if there is no `#version`). Errors in prelude are reported as errors of `<prelude>` file.
+    pub fn from_loader_with_prelude(loader: &FileLoader, files: &[(&str, gl::types::GLenum, Option<&str>)]) -> Result<Program, String> {
+        let mut loaded_files: Vec<(FileIncludes, GLenum)> = vec![];
+
+        for (filepath, shader_type, prelude) in files {
+            let mut content = loader.load_file(filepath)?;
+            if let Some(prelude) = prelude {
+                content.insert_after_version(prelude, Rc::new("<prelude>".to_owned()));
+            }
+            loaded_files.push((content, *shader_type));
+        }
+
+        Self::compile_loaded(&loaded_files, loader.error_formatter())
+    }
+
+    /// Same as `from_loader`, but each stage gets its own `#define NAME VALUE` lines right after `#version`
+    /// (see [`FileLoader::load_file_with_entry_defines`]).
+    ///
+    /// ```rust,no_run
+    /// use shader_loader::{program::Program, preprocessor::FileLoader};
+    ///
+    /// // One file with both stages, selected by `#ifdef VERTEX` / `#ifdef FRAGMENT`
+    /// let loader = FileLoader::new();
+    /// let program = Program::from_loader_with_defines(&loader, &[
+    ///     ("shaders/sprite.glsl", gl::VERTEX_SHADER, &[("VERTEX", "1")]),
+    ///     ("shaders/sprite.glsl", gl::FRAGMENT_SHADER, &[("FRAGMENT", "1")]),
+    /// ]).unwrap();
+    /// ```
+    pub fn from_loader_with_defines(loader: &FileLoader, files: &[(&str, gl::types::GLenum, &Defines)]) -> Result<Program, String> {
+        let loaded_files: Vec<(FileIncludes, GLenum)> = files.iter()
+            .map(|(filepath, shader_type, defines)| {
+                loader.load_file_with_entry_defines(filepath, defines)
+                    .map(|content| (content, *shader_type))
+            })
+            .collect::<Result<_, _>>()?;
+
+        Self::compile_loaded(&loaded_files, loader.error_formatter())
+    }
+
+    /// Compiles already preprocessed units (e.g. cached ones), mapping errors back to original files.
+    /// Errors are formatted with `default_error_formatter`.
+    ///
+    /// ```rust,no_run
+    /// use shader_loader::{program::Program, preprocessor::FileLoader};
+    ///
+    /// let loader = FileLoader::new();
+    /// let mut fragment = loader.load_file("shaders/main.frag").unwrap();
+    /// fragment.hoist_extensions();
+    ///
+    /// let vertex = loader.load_file("shaders/main.vert").unwrap();
+    /// let program = Program::from_includes(&[(vertex, gl::VERTEX_SHADER), (fragment, gl::FRAGMENT_SHADER)]).unwrap();
+    /// ```
+    pub fn from_includes(units: &[(FileIncludes, GLenum)]) -> Result<Program, String> {
+        Self::compile_loaded(units, &default_error_formatter)
+    }
+
+    fn compile_loaded(loaded_files: &[(FileIncludes, GLenum)], formatter: &ErrorFormatter) -> Result<Program, String> {
+        // All stages are compiled even if some of them fail, so errors of every stage are reported at once
+        let mut shaders: Vec<Shader> = vec![];
+        let mut errors: Vec<String> = vec![];
+
+        for (content, shader_type) in loaded_files {
+            match compile_includes(content, *shader_type, formatter) {
+                Ok(shader) => shaders.push(shader),
+                Err(error) => errors.push(error),
+            }
+        }
+
+        if !errors.is_empty() {
+            return Err(errors.join("\n"));
+        }
 
         Self::from_shaders(&shaders)
     }
@@ -103,7 +359,7 @@ impl Program {
             .map(
                 |(path, shader_type)| 
                     Shader::from_file(path.into(), *shader_type)
-                        .map_err(|err| format!("File {path} :: {err}"))    
+                        .map_err(|err| format!("File {path} ({} stage) :: {err}", shader_type_name(*shader_type)))
             )
             .collect();
 
@@ -112,47 +368,221 @@ impl Program {
     }
 
     pub fn from_shaders(shaders: &[Shader]) -> Result<Program, String> {
-		let program_id = unsafe { gl::CreateProgram() };
-
-		for s in shaders {
-			unsafe { gl::AttachShader(program_id, s.id()) };
-		}
-
-		unsafe { gl::LinkProgram(program_id) };
-		let mut success: gl::types::GLint = 1;
-		unsafe {
-		    gl::GetProgramiv(program_id, gl::LINK_STATUS, &mut success);
-		}
-
-		if success == 0 {
-		    let mut len: gl::types::GLint = 0;
-		    unsafe {
-		        gl::GetProgramiv(program_id, gl::INFO_LOG_LENGTH, &mut len);
-		    }
-
-		    let error = create_whitespace_cstring(len as usize);
-
-		    unsafe {
-		        gl::GetProgramInfoLog(
-		            program_id,
-		            len,
-		            std::ptr::null_mut(),
-		            error.as_ptr() as *mut gl::types::GLchar
-		        );
-		    }
-
-		    return Err(error.to_string_lossy().into_owned());
-		}
-
-		for s in shaders {
-			unsafe { gl::DetachShader(program_id, s.id()) };
-		}
-
-        unsafe { gl::UseProgram(program_id); }
-        Ok(Program(program_id))
-	}
+        shaders.iter()
+            .fold(ProgramBuilder::new(), |builder, shader| builder.attach(shader))
+            .link()
+    }
+
+    /// Builds program from SPIR-V binaries: `(binary, shader_type, entry_point)`. See [`Shader::from_spirv`].
+    pub fn from_spirv_shaders(shaders: &[(&[u8], gl::types::GLenum, &str)]) -> Result<Program, String> {
+        let shaders: Vec<Shader> = shaders.iter()
+            .map(|(binary, shader_type, entry_point)| {
+                Shader::from_spirv(binary, *shader_type, entry_point)
+                    .map_err(|err| format!("SPIR-V {} stage :: {err}", shader_type_name(*shader_type)))
+            })
+            .collect::<Result<_, _>>()?;
+
+        Self::from_shaders(&shaders)
+    }
+
+    /// Same as `from_shaders`, but takes ownership of shaders and deletes them right after linking,
+    /// since they are no longer needed by the program.
+    pub fn from_shaders_owned(shaders: Vec<Shader>) -> Result<Program, String> {
+        Self::from_shaders(&shaders)
+    }
+
+    /// Queries program parameter via `glGetProgramiv`
+    pub fn parameter(&self, pname: GLenum) -> gl::types::GLint {
+        let mut value: gl::types::GLint = 0;
+        if self.is_deleted() {
+            return value;
+        }
+        unsafe {
+            gl::GetProgramiv(self.0, pname, &mut value);
+        }
+        value
+    }
+
+    pub fn is_linked(&self) -> bool {
+        self.parameter(gl::LINK_STATUS) != 0
+    }
+
+    /// Primitive type geometry shader accepts (`gl::TRIANGLES`, `gl::POINTS`, ...)
+    ///
+    /// ```rust,no_run
+    /// # let program = shader_loader::program::Program::from_files_auto("billboards").unwrap();
+    /// // `layout(points) in; layout(triangle_strip, max_vertices = 4) out;`
+    /// assert_eq!(program.geometry_input_type(), gl::POINTS);
+    /// assert_eq!(program.geometry_output_type(), gl::TRIANGLE_STRIP);
+    /// assert_eq!(program.geometry_max_vertices(), 4);
+    /// ```
+    pub fn geometry_input_type(&self) -> GLenum {
+        self.parameter(gl::GEOMETRY_INPUT_TYPE) as GLenum
+    }
+
+    /// Primitive type geometry shader emits (`gl::TRIANGLE_STRIP`, `gl::POINTS`, ...)
+    pub fn geometry_output_type(&self) -> GLenum {
+        self.parameter(gl::GEOMETRY_OUTPUT_TYPE) as GLenum
+    }
+
+    pub fn geometry_max_vertices(&self) -> i32 {
+        self.parameter(gl::GEOMETRY_VERTICES_OUT)
+    }
+
+    /// Returns `local_size_x/y/z` of compute shader, or `None` if the program has no compute stage.
+    ///
+    /// The stage can not be checked upfront (shaders are detached after linking), so for linked programs
+    /// without compute stage the query fails, and its `GL_INVALID_OPERATION` is cleared with `glGetError`.
+    /// That also clears an error left by earlier GL calls, so check `glGetError` before calling this.
+    ///
+    /// ```rust,no_run
+    /// # let program = shader_loader::program::Program::from_files_auto("blur").unwrap();
+    /// let (width, height) = (1920u32, 1080u32);
+    /// // `layout(local_size_x = 16, local_size_y = 16) in;`
+    /// let [x, y, _] = program.compute_work_group_size().unwrap();
+    /// unsafe { gl::DispatchCompute(width.div_ceil(x as u32), height.div_ceil(y as u32), 1) };
+    /// ```
+    pub fn compute_work_group_size(&self) -> Option<[i32; 3]> {
+        if self.is_deleted() || !self.is_linked() {
+            return None;
+        }
+        let mut size: [gl::types::GLint; 3] = [0; 3];
+        unsafe {
+            gl::GetProgramiv(self.0, gl::COMPUTE_WORK_GROUP_SIZE, size.as_mut_ptr());
+        }
+
+        if size.contains(&0) {
+            // Query is left untouched with GL_INVALID_OPERATION for non-compute programs. Clear the error we caused
+            unsafe { gl::GetError(); }
+            None
+        } else {
+            Some(size)
+        }
+    }
+
+    /// Queries current info log of the program. Can be called at any moment, not only after a failed link.
+    ///
+    /// ```rust,no_run
+    /// # let program = shader_loader::program::Program::from_files_auto("shader").unwrap();
+    /// assert!(program.is_linked());
+    /// // Linker warnings, if the driver reports any
+    /// println!("{}", program.info_log());
+    /// ```
+    pub fn info_log(&self) -> String {
+        if self.is_deleted() {
+            return String::new();
+        }
+        let len = self.parameter(gl::INFO_LOG_LENGTH);
+        let log = create_whitespace_cstring(len.max(0) as usize);
+
+        unsafe {
+            gl::GetProgramInfoLog(
+                self.0,
+                len,
+                std::ptr::null_mut(),
+                log.as_ptr() as *mut gl::types::GLchar
+            );
+        }
+
+        log.to_string_lossy().into_owned()
+    }
+
+    /// Ids of shaders currently attached to the program (`glGetAttachedShaders`).
+    /// Programs made by [`ProgramBuilder`] detach shaders after linking, so this is empty for them.
+    ///
+    /// ```rust,no_run
+    /// # use shader_loader::{program::Program, shader::Shader};
+    /// # let (program, shader): (Program, Shader) = unimplemented!();
+    /// unsafe { gl::AttachShader(program.id(), shader.id()) };
+    /// assert_eq!(program.attached_shaders(), [shader.id()]);
+    /// ```
+    pub fn attached_shaders(&self) -> Vec<gl::types::GLuint> {
+        let count = self.parameter(gl::ATTACHED_SHADERS);
+        let mut shaders = vec![0; count.max(0) as usize];
+        let mut written: gl::types::GLsizei = 0;
+
+        if count > 0 {
+            unsafe {
+                gl::GetAttachedShaders(self.0, count, &mut written, shaders.as_mut_ptr());
+            }
+        }
+
+        shaders.truncate(written.max(0) as usize);
+        shaders
+    }
+
+    /// Detaches all shaders, which are still attached, so the program and shaders can be dropped in any order.
+    /// Programs made by this crate detach shaders right after linking, so this only matters for shaders
+    /// attached manually via `gl::AttachShader(program.id(), ...)`.
+    ///
+    /// ```rust,no_run
+    /// # use shader_loader::{program::Program, shader::Shader};
+    /// # let (program, shader): (Program, Shader) = unimplemented!();
+    /// unsafe { gl::AttachShader(program.id(), shader.id()) };
+    /// let program = program.into_standalone();
+    /// drop(shader); // Deleted right away, instead of when the program is deleted
+    /// ```
+    pub fn into_standalone(self) -> Program {
+        for shader in self.attached_shaders() {
+            unsafe { gl::DetachShader(self.0, shader) };
+        }
+        self
+    }
+
+    /// Number of active attributes, including built-in `gl_` ones
+    pub fn num_active_attributes(&self) -> i32 {
+        self.parameter(gl::ACTIVE_ATTRIBUTES)
+    }
+
+    /// Lists active vertex attributes of the program. Built-in `gl_` attributes are skipped.
+    ///
+    /// ```rust,no_run
+    /// # let program = shader_loader::program::Program::from_files_auto("shader").unwrap();
+    /// for attribute in program.active_attributes() {
+    ///     println!("{} at location {} (type 0x{:04X}, size {})", attribute.name, attribute.location, attribute.gl_type, attribute.size);
+    /// }
+    /// ```
+    pub fn active_attributes(&self) -> Vec<AttributeInfo> {
+        let max_length = self.parameter(gl::ACTIVE_ATTRIBUTE_MAX_LENGTH);
+
+        let mut attributes = vec![];
+        for index in 0..self.num_active_attributes() {
+            let mut name = vec![0u8; max_length.max(1) as usize];
+            let mut length: gl::types::GLsizei = 0;
+            let mut size: gl::types::GLint = 0;
+            let mut gl_type: GLenum = 0;
+
+            unsafe {
+                gl::GetActiveAttrib(
+                    self.0,
+                    index as u32,
+                    name.len() as i32,
+                    &mut length,
+                    &mut size,
+                    &mut gl_type,
+                    name.as_mut_ptr() as *mut gl::types::GLchar
+                );
+            }
+
+            name.truncate(length.max(0) as usize);
+            let name = String::from_utf8_lossy(&name).into_owned();
+            if name.starts_with("gl_") {
+                continue;
+            }
+
+            let c_name = CString::new(name.as_str()).unwrap();
+            let location = unsafe { gl::GetAttribLocation(self.0, c_name.as_ptr()) };
+
+            attributes.push(AttributeInfo { name, gl_type, size, location });
+        }
+
+        attributes
+    }
 
     pub fn use_program(&self) {
+        if self.is_deleted() {
+            return;
+        }
         unsafe {
             gl::UseProgram(self.0);
         }
@@ -162,76 +592,799 @@ impl Program {
         self.0
     }
 
+    /// Deletes the program before it is dropped. After that all the methods, which use the program, do nothing.
+    ///
+    /// ```rust,no_run
+    /// # let mut program = shader_loader::program::Program::from_files_auto("shader").unwrap();
+    /// program.delete();
+    /// assert!(program.is_deleted());
+    /// // Does nothing, instead of using the id GL may have given to another program
+    /// program.uniform("u_time", 1.0f32);
+    /// ```
+    pub fn delete(&mut self) {
+        if !self.is_deleted() {
+            unsafe { gl::DeleteProgram(self.0) };
+            self.0 = 0;
+        }
+    }
+
+    pub fn is_deleted(&self) -> bool {
+        self.0 == 0
+    }
+
+    /// Binds the program and dispatches compute work groups, reading group counts from the buffer
+    /// bound to `gl::DISPATCH_INDIRECT_BUFFER` at `indirect_offset` bytes.
+    /// 
+    /// The buffer has to be bound beforehand, and contain three `u32` values: `num_groups_x/y/z`.
+    ///
+    /// ```rust,no_run
+    /// # let program = shader_loader::program::Program::from_files_auto("particles").unwrap();
+    /// # let buffer: gl::types::GLuint = 0;
+    /// unsafe { gl::BindBuffer(gl::DISPATCH_INDIRECT_BUFFER, buffer) };
+    /// // Group counts of the second dispatch, written by an earlier pass
+    /// program.dispatch_compute_indirect(3 * std::mem::size_of::<u32>() as isize);
+    /// ```
+    pub fn dispatch_compute_indirect(&self, indirect_offset: isize) {
+        if self.is_deleted() {
+            return;
+        }
+        self.use_program();
+        unsafe {
+            gl::DispatchComputeIndirect(indirect_offset);
+        }
+    }
+
+    /// Binds the program and sets uniform `name`. Arrays of vectors are uploaded from slices:
+    ///
+    /// ```rust,no_run
+    /// # let program = shader_loader::program::Program::from_files_auto("shader").unwrap();
+    /// let offsets: [[i32; 2]; 4] = [[-1, 0], [1, 0], [0, -1], [0, 1]];
+    /// program.uniform("u_offsets", &offsets[..]); // ivec2[4]
+    /// program.uniform("u_tile", [3u32, 7]); // uvec2
+    /// ```
     pub fn uniform<T: Uniformable>(&self, name: &str, val: T) {
+        if self.is_deleted() {
+            return;
+        }
         self.use_program();
         let location = gl_get_uniform_location(self, name);
         unsafe { 
             val.set_uniform(location); 
         }
     }
+
+    /// Same as `uniform`, but does not change currently bound program. Uses `glProgramUniform*`
+    /// when it is available (GL 4.1 or `GL_ARB_separate_shader_objects`), otherwise the program is bound
+    /// temporarily and previous one is restored.
+    ///
+    /// ```rust,no_run
+    /// # use shader_loader::program::Program;
+    /// # let (scene, postprocess): (Program, Program) = unimplemented!();
+    /// scene.use_program();
+    /// // Updated without rebinding, `scene` stays bound
+    /// postprocess.program_uniform("u_exposure", 1.5f32);
+    /// ```
+    pub fn program_uniform<T: Uniformable>(&self, name: &str, val: T) {
+        if self.is_deleted() {
+            return;
+        }
+        let location = gl_get_uniform_location(self, name);
+        unsafe {
+            match has_program_uniform() {
+                true => val.set_program_uniform(self.id(), location),
+                false => set_uniform_with_bound_program(val, self.id(), location),
+            }
+        }
+    }
     
+    /// Reads current value of `float` uniform. `None` if there is no such uniform, or it is not a scalar
+    ///
+    /// ```rust,no_run
+    /// # let program: shader_loader::program::Program = unimplemented!();
+    /// program.uniform("u_time", 1.5f32);
+    /// assert_eq!(program.get_uniform_f32("u_time"), Some(1.5));
+    /// assert_eq!(program.get_uniform_f32_array::<3>("u_color"), Some([1.0, 0.5, 0.0])); // vec3
+    /// assert_eq!(program.get_uniform_f32("u_color"), None); // Not a scalar
+    /// ```
+    pub fn get_uniform_f32(&self, name: &str) -> Option<f32> {
+        self.get_uniform_f32_array::<1>(name).map(|[value]| value)
+    }
+
+    /// Reads current value of `int` (or sampler) uniform. `None` if there is no such uniform, or it is not a scalar
+    pub fn get_uniform_i32(&self, name: &str) -> Option<i32> {
+        self.get_uniform_i32_array::<1>(name).map(|[value]| value)
+    }
+
+    /// Reads current value of `uint` uniform. `None` if there is no such uniform, or it is not a scalar
+    pub fn get_uniform_u32(&self, name: &str) -> Option<u32> {
+        self.get_uniform_u32_array::<1>(name).map(|[value]| value)
+    }
+
+    /// Reads current value of `vecN` (or `matN`, in column-major order) uniform.
+    /// GL writes all components of the uniform, so `None` is returned if `N` is not their number.
+    pub fn get_uniform_f32_array<const N: usize>(&self, name: &str) -> Option<[f32; N]> {
+        let mut values = [0.0; N];
+        let location = self.readable_location(name, N)?;
+        unsafe { gl::GetUniformfv(self.0, location, values.as_mut_ptr()) };
+        Some(values)
+    }
+
+    /// Reads current value of `ivecN` uniform
+    pub fn get_uniform_i32_array<const N: usize>(&self, name: &str) -> Option<[i32; N]> {
+        let mut values = [0; N];
+        let location = self.readable_location(name, N)?;
+        unsafe { gl::GetUniformiv(self.0, location, values.as_mut_ptr()) };
+        Some(values)
+    }
+
+    /// Reads current value of `uvecN` uniform
+    pub fn get_uniform_u32_array<const N: usize>(&self, name: &str) -> Option<[u32; N]> {
+        let mut values = [0; N];
+        let location = self.readable_location(name, N)?;
+        unsafe { gl::GetUniformuiv(self.0, location, values.as_mut_ptr()) };
+        Some(values)
+    }
+
+    /// Location of uniform `name`, if it has exactly `components` components, so reading it fills the buffer exactly
+    fn readable_location(&self, name: &str, components: usize) -> Option<i32> {
+        if self.is_deleted() || uniform_components(self.uniform_type(name)?) != components {
+            return None;
+        }
+        Some(gl_get_uniform_location(self, name)).filter(|location| *location != -1)
+    }
+
+    /// Type of active uniform `name` (`gl::FLOAT_VEC3`, ...). Elements of arrays, like `lights[2]`, have type of the array
+    fn uniform_type(&self, name: &str) -> Option<GLenum> {
+        let array = name.strip_suffix(']')
+            .and_then(|name| name.rsplit_once('['))
+            .map(|(array, _)| array);
+        let index = self.uniform_index(name)
+            .or_else(|| array.and_then(|array| self.uniform_index(array)))?;
+
+        let mut gl_type: gl::types::GLint = 0;
+        unsafe {
+            gl::GetActiveUniformsiv(self.0, 1, &index, gl::UNIFORM_TYPE, &mut gl_type);
+        }
+        Some(gl_type as GLenum)
+    }
+
+    /// Index of active uniform `name` (`glGetUniformIndices`)
+    fn uniform_index(&self, name: &str) -> Option<gl::types::GLuint> {
+        let c_name = CString::new(name).ok()?;
+        let mut index: gl::types::GLuint = gl::INVALID_INDEX;
+        unsafe {
+            gl::GetUniformIndices(self.0, 1, &c_name.as_ptr(), &mut index);
+        }
+        (index != gl::INVALID_INDEX).then_some(index)
+    }
+
+    /// Sets element `index` of uniform array `base`, i.e. uniform `base[index]`
+    ///
+    /// ```rust,no_run
+    /// # let program = shader_loader::program::Program::from_files_auto("shader").unwrap();
+    /// let lights = [[1.0f32, 0.0, 0.0], [0.0, 1.0, 0.0]];
+    /// for (i, color) in lights.iter().enumerate() {
+    ///     program.uniform_indexed("u_light_colors", i, color); // u_light_colors[i]
+    /// }
+    /// ```
+    pub fn uniform_indexed<T: Uniformable>(&self, base: &str, index: usize, val: T) {
+        self.uniform(&format!("{base}[{index}]"), val);
+    }
+
+    /// Same as `uniform`, but returns `false` if there is no active uniform `name` (so nothing was set)
+    ///
+    /// ```rust,no_run
+    /// # let program = shader_loader::program::Program::from_files_auto("shader").unwrap();
+    /// // Unused uniforms are removed by the compiler, so a typo and an optimized out uniform look the same
+    /// if !program.uniform_checked("u_exposure", 1.5f32) {
+    ///     eprintln!("u_exposure is not used by the shader");
+    /// }
+    /// ```
+    pub fn uniform_checked<T: Uniformable>(&self, name: &str, val: T) -> bool {
+        if self.is_deleted() {
+            return false;
+        }
+        let location = gl_get_uniform_location(self, name);
+        if location == -1 {
+            return false;
+        }
+        self.use_program();
+        unsafe {
+            val.set_uniform(location);
+        }
+        true
+    }
+
+    /// Sets `mat4` uniform. `transpose` has to be set if `matrix` is row-major (each inner array is a row)
+    ///
+    /// ```rust,no_run
+    /// # let program = shader_loader::program::Program::from_files_auto("shader").unwrap();
+    /// // Row-major, as matrices are usually written: translation is in the last column
+    /// let model = [
+    ///     [1.0, 0.0, 0.0, 5.0],
+    ///     [0.0, 1.0, 0.0, 0.0],
+    ///     [0.0, 0.0, 1.0, 0.0],
+    ///     [0.0, 0.0, 0.0, 1.0],
+    /// ];
+    /// program.uniform_mat4("u_model", &model, true);
+    /// ```
+    pub fn uniform_mat4(&self, name: &str, matrix: &[[f32; 4]; 4], transpose: bool) {
+        self.uniform_matrix(name, |location, transpose| unsafe {
+            gl::UniformMatrix4fv(location, 1, transpose, matrix.as_ptr() as *const f32)
+        }, transpose);
+    }
+
+    /// Sets `mat3` uniform. `transpose` has to be set if `matrix` is row-major (each inner array is a row)
+    pub fn uniform_mat3(&self, name: &str, matrix: &[[f32; 3]; 3], transpose: bool) {
+        self.uniform_matrix(name, |location, transpose| unsafe {
+            gl::UniformMatrix3fv(location, 1, transpose, matrix.as_ptr() as *const f32)
+        }, transpose);
+    }
+
+    /// Sets `mat2` uniform. `transpose` has to be set if `matrix` is row-major (each inner array is a row)
+    pub fn uniform_mat2(&self, name: &str, matrix: &[[f32; 2]; 2], transpose: bool) {
+        self.uniform_matrix(name, |location, transpose| unsafe {
+            gl::UniformMatrix2fv(location, 1, transpose, matrix.as_ptr() as *const f32)
+        }, transpose);
+    }
+
+    /// Sets `mat4[]` uniform (e.g. bone palette) from `matrices`, count is taken from the slice.
+    /// To update only a part of the array, pass a subslice and name of its first element, like `bones[5]`
+    ///
+    /// ```rust,no_run
+    /// # let program = shader_loader::program::Program::from_files_auto("skinning").unwrap();
+    /// let mut bones = [[[0.0f32; 4]; 4]; 64];
+    /// program.uniform_mat4_array("u_bones", &bones, false);
+    ///
+    /// // Only bones 5..8 have moved
+    /// bones[5][3][0] = 1.0;
+    /// program.uniform_mat4_array("u_bones[5]", &bones[5..8], false);
+    /// ```
+    pub fn uniform_mat4_array(&self, name: &str, matrices: &[[[f32; 4]; 4]], transpose: bool) {
+        self.uniform_matrix(name, |location, transpose| unsafe {
+            gl::UniformMatrix4fv(location, matrices.len() as i32, transpose, matrices.as_ptr() as *const f32)
+        }, transpose);
+    }
+
+    fn uniform_matrix(&self, name: &str, set: impl FnOnce(i32, gl::types::GLboolean), transpose: bool) {
+        if self.is_deleted() {
+            return;
+        }
+        self.use_program();
+        let location = gl_get_uniform_location(self, name);
+        set(location, if transpose { gl::TRUE } else { gl::FALSE });
+    }
+
+    /// Sets image uniform (like `image2D`) to image unit `unit`. The texture has to be bound to the unit
+    /// with `gl::BindImageTexture` separately.
+    ///
+    /// ```rust,no_run
+    /// # let program = shader_loader::program::Program::from_files_auto("blur").unwrap();
+    /// # let texture: gl::types::GLuint = 0;
+    /// // `layout(rgba8) uniform writeonly image2D u_output;`
+    /// unsafe { gl::BindImageTexture(2, texture, 0, gl::FALSE, 0, gl::WRITE_ONLY, gl::RGBA8) };
+    /// program.image_uniform("u_output", 2);
+    /// ```
+    pub fn image_uniform(&self, name: &str, unit: u32) {
+        self.uniform(name, unit as i32);
+    }
+
+    pub fn set_uniforms<T: Uniforms + ?Sized>(&self, uniforms: &T) {
+        uniforms.apply(self);
+    }
+
+    /// Size of uniform block in bytes (`GL_UNIFORM_BLOCK_DATA_SIZE`)
+    ///
+    /// ```rust,no_run
+    /// # let program = shader_loader::program::Program::from_files_auto("shader").unwrap();
+    /// // `layout(std140) uniform Camera { mat4 view; vec3 position; };`
+    /// let block_index = unsafe { gl::GetUniformBlockIndex(program.id(), c"Camera".as_ptr()) };
+    /// assert_eq!(program.uniform_block_size(block_index), 80);
+    /// assert_eq!(program.uniform_member_offset("position"), Some(64));
+    /// ```
+    pub fn uniform_block_size(&self, block_index: u32) -> i32 {
+        let mut size: gl::types::GLint = 0;
+        if self.is_deleted() {
+            return size;
+        }
+        unsafe {
+            gl::GetActiveUniformBlockiv(self.0, block_index, gl::UNIFORM_BLOCK_DATA_SIZE, &mut size);
+        }
+        size
+    }
+
+    /// Byte offset of uniform block member inside its block. `None` if there is no such uniform,
+    /// or it does not belong to a block.
+    pub fn uniform_member_offset(&self, name: &str) -> Option<i32> {
+        if self.is_deleted() {
+            return None;
+        }
+        let index = self.uniform_index(name)?;
+
+        let mut offset: gl::types::GLint = -1;
+        unsafe {
+            gl::GetActiveUniformsiv(self.0, 1, &index, gl::UNIFORM_OFFSET, &mut offset);
+        }
+
+        (offset >= 0).then_some(offset)
+    }
+
+    /// Location of uniform `name`, or -1 if there is no such uniform or the program is deleted
     pub fn location(&self, name: &str) -> i32 {
+        if self.is_deleted() {
+            return -1;
+        }
         gl_get_uniform_location(self, name)
-    } 
+    }
+
+    /// Looks up uniform location once, so it can be set via `set` without name lookups (e.g. every frame)
+    ///
+    /// ```rust,no_run
+    /// # let program = shader_loader::program::Program::from_files_auto("shader").unwrap();
+    /// let time = program.location_handle("u_time");
+    /// for frame in 0..1000 {
+    ///     program.set(time, frame as f32 / 60.0);
+    ///     // draw ...
+    /// }
+    /// ```
+    pub fn location_handle(&self, name: &str) -> Location {
+        Location(self.location(name))
+    }
+
+    /// Sets uniform by location from `location_handle` of this program
+    pub fn set<T: Uniformable>(&self, location: Location, val: T) {
+        if self.is_deleted() {
+            return;
+        }
+        self.use_program();
+        unsafe {
+            val.set_uniform(location.0);
+        }
+    }
 }
 
 impl Drop for Program {
     fn drop(&mut self) {
-        unsafe {
-            gl::DeleteProgram(self.0);
+        self.delete();
+    }
+}
+
+/// Allows to configure state of the program, that has to be set between attaching shaders and linking.
+/// 
+/// ```rust,no_run
+/// use shader_loader::{shader::Shader, program::ProgramBuilder};
+/// # let vertex: Shader = unimplemented!();
+/// let program = ProgramBuilder::new()
+///     .attach(&vertex)
+///     .transform_feedback_varyings(&["out_position"], gl::INTERLEAVED_ATTRIBS)
+///     .link()
+///     .unwrap();
+/// ```
+#[derive(Default)]
+pub struct ProgramBuilder<'a> {
+    shaders: Vec<&'a Shader>,
+    feedback_varyings: Vec<CString>,
+    feedback_mode: GLenum,
+    frag_data_locations: Vec<(u32, CString)>,
+    attribute_locations: Vec<(u32, CString)>,
+    separable: bool,
+}
+
+impl<'a> ProgramBuilder<'a> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn attach(mut self, shader: &'a Shader) -> Self {
+        self.shaders.push(shader);
+        self
+    }
+
+    /// Ids of shaders, which will be attached on `link`, in order they were added
+    ///
+    /// ```rust,no_run
+    /// # use shader_loader::{program::ProgramBuilder, shader::Shader};
+    /// # let (vertex, fragment): (Shader, Shader) = unimplemented!();
+    /// let builder = ProgramBuilder::new().attach(&vertex).attach(&fragment);
+    /// assert_eq!(builder.attached_shaders(), [vertex.id(), fragment.id()]);
+    /// ```
+    pub fn attached_shaders(&self) -> Vec<gl::types::GLuint> {
+        self.shaders.iter().map(|shader| shader.id()).collect()
+    }
+
+    /// `mode` is either `gl::INTERLEAVED_ATTRIBS` or `gl::SEPARATE_ATTRIBS`
+    ///
+    /// ```rust,no_run
+    /// # use shader_loader::{shader::Shader, program::ProgramBuilder};
+    /// # let vertex: Shader = unimplemented!();
+    /// // Each varying is captured into its own buffer binding (0 and 1)
+    /// let program = ProgramBuilder::new()
+    ///     .attach(&vertex)
+    ///     .transform_feedback_varyings(&["out_position", "out_velocity"], gl::SEPARATE_ATTRIBS)
+    ///     .link()
+    ///     .unwrap();
+    /// ```
+    pub fn transform_feedback_varyings(mut self, varyings: &[&str], mode: GLenum) -> Self {
+        self.feedback_varyings = varyings.iter()
+            .map(|name| CString::new(*name).unwrap())
+            .collect();
+        self.feedback_mode = mode;
+        self
+    }
+
+    /// Binds fragment shader output `name` to color attachment `index` (for multiple render targets)
+    ///
+    /// ```rust,no_run
+    /// # use shader_loader::{shader::Shader, program::ProgramBuilder};
+    /// # let (vertex, fragment): (Shader, Shader) = unimplemented!();
+    /// // `out vec4 out_albedo; out vec4 out_normal;` written to the first two color attachments of G-buffer
+    /// let program = ProgramBuilder::new()
+    ///     .attach(&vertex)
+    ///     .attach(&fragment)
+    ///     .bind_frag_data_location(0, "out_albedo")
+    ///     .bind_frag_data_location(1, "out_normal")
+    ///     .link()
+    ///     .unwrap();
+    /// ```
+    pub fn bind_frag_data_location(mut self, index: u32, name: &str) -> Self {
+        self.frag_data_locations.push((index, CString::new(name).unwrap()));
+        self
+    }
+
+    /// Binds vertex shader input `name` to attribute `index`. Explicit `layout(location)` in shader takes precedence
+    pub fn bind_attribute(mut self, index: u32, name: &str) -> Self {
+        self.attribute_locations.push((index, CString::new(name).unwrap()));
+        self
+    }
+
+    /// Makes the program separable (`GL_PROGRAM_SEPARABLE`), so its stages can be used in [`ProgramPipeline`]
+    pub fn separable(mut self, separable: bool) -> Self {
+        self.separable = separable;
+        self
+    }
+
+    pub fn link(self) -> Result<Program, String> {
+        let program_id = unsafe { gl::CreateProgram() };
+        let program = Program(program_id, PhantomData);
+
+        for s in self.shaders.iter() {
+            unsafe { gl::AttachShader(program_id, s.id()) };
+        }
+
+        if !self.feedback_varyings.is_empty() {
+            let varyings: Vec<_> = self.feedback_varyings.iter()
+                .map(|name| name.as_ptr())
+                .collect();
+            unsafe {
+                gl::TransformFeedbackVaryings(program_id, varyings.len() as i32, varyings.as_ptr(), self.feedback_mode);
+            }
+        }
+
+        for (index, name) in self.frag_data_locations.iter() {
+            unsafe { gl::BindFragDataLocation(program_id, *index, name.as_ptr()) };
+        }
+
+        for (index, name) in self.attribute_locations.iter() {
+            unsafe { gl::BindAttribLocation(program_id, *index, name.as_ptr()) };
+        }
+
+        if self.separable {
+            unsafe { gl::ProgramParameteri(program_id, gl::PROGRAM_SEPARABLE, gl::TRUE as i32) };
+        }
+
+        unsafe { gl::LinkProgram(program_id) };
+
+        if !program.is_linked() {
+            return Err(program.info_log());
+        }
+
+        for s in self.shaders.iter() {
+            unsafe { gl::DetachShader(program_id, s.id()) };
         }
+
+        program.use_program();
+        Ok(program)
+    }
+}
+
+/// Program pipeline object, which combines stages of separable programs (see [`ProgramBuilder::separable`])
+///
+/// ```rust,no_run
+/// use shader_loader::{shader::Shader, program::{ProgramBuilder, ProgramPipeline}};
+/// # let (vertex, fragment): (Shader, Shader) = unimplemented!();
+///
+/// let vertex_program = ProgramBuilder::new().attach(&vertex).separable(true).link().unwrap();
+/// let fragment_program = ProgramBuilder::new().attach(&fragment).separable(true).link().unwrap();
+///
+/// let pipeline = ProgramPipeline::new();
+/// pipeline.use_stages(gl::VERTEX_SHADER_BIT, &vertex_program);
+/// pipeline.use_stages(gl::FRAGMENT_SHADER_BIT, &fragment_program);
+/// unsafe { gl::UseProgram(0) };
+/// pipeline.bind();
+/// ```
+pub struct ProgramPipeline(gl::types::GLuint, crate::NotSend);
+
+impl ProgramPipeline {
+    pub fn new() -> Self {
+        let mut id: gl::types::GLuint = 0;
+        unsafe { gl::GenProgramPipelines(1, &mut id) };
+        ProgramPipeline(id, PhantomData)
+    }
+
+    /// Uses `stages` of `program` in this pipeline. `stages` is a mask like `gl::VERTEX_SHADER_BIT | gl::FRAGMENT_SHADER_BIT`
+    pub fn use_stages(&self, stages: gl::types::GLbitfield, program: &Program) {
+        unsafe { gl::UseProgramStages(self.0, stages, program.id()) };
+    }
+
+    /// Binds the pipeline. It is only used if no program is bound via `Program::use_program`
+    pub fn bind(&self) {
+        unsafe { gl::BindProgramPipeline(self.0) };
+    }
+
+    pub fn id(&self) -> gl::types::GLuint {
+        self.0
+    }
+}
+
+impl Default for ProgramPipeline {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Drop for ProgramPipeline {
+    fn drop(&mut self) {
+        unsafe { gl::DeleteProgramPipelines(1, &self.0) };
     }
 }
 
 macro_rules! uniformable {
-    ($type:ty, $function_name:expr) => {
+    ($type:ty, $function_name:expr, $program_function_name:expr) => {
         impl Uniformable for $type {
             unsafe fn set_uniform(self, location: i32) {
                 $function_name (location, self)
             }
+
+            unsafe fn set_program_uniform(self, program: gl::types::GLuint, location: i32) {
+                $program_function_name (program, location, self)
+            }
         }
     };
 
-    ($type:ty, $function_name:expr, 2) => {
+    ($type:ty, $function_name:expr, $program_function_name:expr, 2) => {
         impl Uniformable for $type {
             unsafe fn set_uniform(self, location: i32) {
                 $function_name (location, self.0, self.1)
             }
+
+            unsafe fn set_program_uniform(self, program: gl::types::GLuint, location: i32) {
+                $program_function_name (program, location, self.0, self.1)
+            }
         }
     };
     
-    ($type:ty, $function_name:expr, 3) => {
+    ($type:ty, $function_name:expr, $program_function_name:expr, 3) => {
         impl Uniformable for $type {
             unsafe fn set_uniform(self, location: i32) {
                 $function_name (location, self.0, self.1, self.2)
             }
+
+            unsafe fn set_program_uniform(self, program: gl::types::GLuint, location: i32) {
+                $program_function_name (program, location, self.0, self.1, self.2)
+            }
         }
     };
 
     
-    ($type:ty, $function_name:expr, 4) => {
+    ($type:ty, $function_name:expr, $program_function_name:expr, 4) => {
         impl Uniformable for $type {
             unsafe fn set_uniform(self, location: i32) {
                 $function_name (location, self.0, self.1, self.2, self.3)
             }
+
+            unsafe fn set_program_uniform(self, program: gl::types::GLuint, location: i32) {
+                $program_function_name (program, location, self.0, self.1, self.2, self.3)
+            }
+        }
+    };
+
+    ($type:ty, $function_name:expr, $program_function_name:expr, [2]) => {
+        impl Uniformable for $type {
+            unsafe fn set_uniform(self, location: i32) {
+                $function_name (location, self[0], self[1])
+            }
+
+            unsafe fn set_program_uniform(self, program: gl::types::GLuint, location: i32) {
+                $program_function_name (program, location, self[0], self[1])
+            }
+        }
+    };
+
+    ($type:ty, $function_name:expr, $program_function_name:expr, [3]) => {
+        impl Uniformable for $type {
+            unsafe fn set_uniform(self, location: i32) {
+                $function_name (location, self[0], self[1], self[2])
+            }
+
+            unsafe fn set_program_uniform(self, program: gl::types::GLuint, location: i32) {
+                $program_function_name (program, location, self[0], self[1], self[2])
+            }
+        }
+    };
+
+    ($type:ty, $function_name:expr, $program_function_name:expr, [4]) => {
+        impl Uniformable for $type {
+            unsafe fn set_uniform(self, location: i32) {
+                $function_name (location, self[0], self[1], self[2], self[3])
+            }
+
+            unsafe fn set_program_uniform(self, program: gl::types::GLuint, location: i32) {
+                $program_function_name (program, location, self[0], self[1], self[2], self[3])
+            }
+        }
+    };
+
+    // Array of vectors. Nested arrays are contiguous, so slice can be passed as is
+    ($type:ty, $function_name:expr, $program_function_name:expr, [..]) => {
+        impl Uniformable for $type {
+            unsafe fn set_uniform(self, location: i32) {
+                $function_name (location, self.len() as i32, self.as_ptr() as *const _)
+            }
+
+            unsafe fn set_program_uniform(self, program: gl::types::GLuint, location: i32) {
+                $program_function_name (program, location, self.len() as i32, self.as_ptr() as *const _)
+            }
         }
     };
 }
 
-uniformable!(f32, gl::Uniform1f);
-uniformable!((f32, f32), gl::Uniform2f, 2);
-uniformable!((f32, f32, f32), gl::Uniform3f, 3);
-uniformable!((f32, f32, f32, f32), gl::Uniform4f, 4);
+uniformable!(f32, gl::Uniform1f, gl::ProgramUniform1f);
+uniformable!((f32, f32), gl::Uniform2f, gl::ProgramUniform2f, 2);
+uniformable!((f32, f32, f32), gl::Uniform3f, gl::ProgramUniform3f, 3);
+uniformable!((f32, f32, f32, f32), gl::Uniform4f, gl::ProgramUniform4f, 4);
+
+uniformable!(u32, gl::Uniform1ui, gl::ProgramUniform1ui);
+uniformable!((u32, u32), gl::Uniform2ui, gl::ProgramUniform2ui, 2);
+uniformable!((u32, u32, u32), gl::Uniform3ui, gl::ProgramUniform3ui, 3);
+uniformable!((u32, u32, u32, u32), gl::Uniform4ui, gl::ProgramUniform4ui, 4);
+
+uniformable!(i32, gl::Uniform1i, gl::ProgramUniform1i);
+uniformable!((i32, i32), gl::Uniform2i, gl::ProgramUniform2i, 2);
+uniformable!((i32, i32, i32), gl::Uniform3i, gl::ProgramUniform3i, 3);
+uniformable!((i32, i32, i32, i32), gl::Uniform4i, gl::ProgramUniform4i, 4);
+
+uniformable!([f32; 2], gl::Uniform2f, gl::ProgramUniform2f, [2]);
+uniformable!([f32; 3], gl::Uniform3f, gl::ProgramUniform3f, [3]);
+uniformable!([f32; 4], gl::Uniform4f, gl::ProgramUniform4f, [4]);
+
+uniformable!([u32; 2], gl::Uniform2ui, gl::ProgramUniform2ui, [2]);
+uniformable!([u32; 3], gl::Uniform3ui, gl::ProgramUniform3ui, [3]);
+uniformable!([u32; 4], gl::Uniform4ui, gl::ProgramUniform4ui, [4]);
+
+uniformable!([i32; 2], gl::Uniform2i, gl::ProgramUniform2i, [2]);
+uniformable!([i32; 3], gl::Uniform3i, gl::ProgramUniform3i, [3]);
+uniformable!([i32; 4], gl::Uniform4i, gl::ProgramUniform4i, [4]);
+
+uniformable!(&[[u32; 2]], gl::Uniform2uiv, gl::ProgramUniform2uiv, [..]);
+uniformable!(&[[u32; 3]], gl::Uniform3uiv, gl::ProgramUniform3uiv, [..]);
+uniformable!(&[[u32; 4]], gl::Uniform4uiv, gl::ProgramUniform4uiv, [..]);
+
+uniformable!(&[[i32; 2]], gl::Uniform2iv, gl::ProgramUniform2iv, [..]);
+uniformable!(&[[i32; 3]], gl::Uniform3iv, gl::ProgramUniform3iv, [..]);
+uniformable!(&[[i32; 4]], gl::Uniform4iv, gl::ProgramUniform4iv, [..]);
+
+/// Any value can be passed by reference, and is uploaded the same way as the value itself
+/// (`&[f32; 3]` is `vec3` and `&[f32; 16]` is `mat4`). Uniform arrays are uploaded from slices without copying:
+///
+/// ```rust,no_run
+/// # let program = shader_loader::program::Program::from_files_auto("shader").unwrap();
+/// let color = [1.0f32, 0.5, 0.0];
+/// program.uniform("u_color", &color);
+///
+/// let bones = [[[0.0f32; 4]; 4]; 64];
+/// program.uniform("u_bones", &bones[..]);
+///
+/// let weights = [0.0f32; 256];
+/// program.uniform("u_weights", &weights[..]);
+/// program.uniform("u_weights", &weights[..16]);
+/// ```
+impl<T: Uniformable + Copy> Uniformable for &T {
+    unsafe fn set_uniform(self, location: i32) {
+        (*self).set_uniform(location)
+    }
+
+    unsafe fn set_program_uniform(self, program: gl::types::GLuint, location: i32) {
+        (*self).set_program_uniform(program, location)
+    }
+}
+
+// `None` leaves the uniform as it is, so it can be updated conditionally
+impl<T: Uniformable> Uniformable for Option<T> {
+    unsafe fn set_uniform(self, location: i32) {
+        if let Some(value) = self {
+            value.set_uniform(location)
+        }
+    }
+
+    unsafe fn set_program_uniform(self, program: gl::types::GLuint, location: i32) {
+        if let Some(value) = self {
+            value.set_program_uniform(program, location)
+        }
+    }
+}
+
+impl Uniformable for RgbaU8 {
+    unsafe fn set_uniform(self, location: i32) {
+        self.normalized().set_uniform(location)
+    }
+
+    unsafe fn set_program_uniform(self, program: gl::types::GLuint, location: i32) {
+        self.normalized().set_program_uniform(program, location)
+    }
+}
+
+// Arrays of scalars are uploaded straight from the slice, without copying
+impl Uniformable for &[f32] {
+    unsafe fn set_uniform(self, location: i32) {
+        gl::Uniform1fv(location, self.len() as i32, self.as_ptr())
+    }
+
+    unsafe fn set_program_uniform(self, program: gl::types::GLuint, location: i32) {
+        gl::ProgramUniform1fv(program, location, self.len() as i32, self.as_ptr())
+    }
+}
 
-uniformable!(u32, gl::Uniform1ui);
-uniformable!((u32, u32), gl::Uniform2ui, 2);
-uniformable!((u32, u32, u32), gl::Uniform3ui, 3);
-uniformable!((u32, u32, u32, u32), gl::Uniform4ui, 4);
+impl Uniformable for &[u32] {
+    unsafe fn set_uniform(self, location: i32) {
+        gl::Uniform1uiv(location, self.len() as i32, self.as_ptr())
+    }
 
-uniformable!(i32, gl::Uniform1i);
-uniformable!((i32, i32), gl::Uniform2i, 2);
-uniformable!((i32, i32, i32), gl::Uniform3i, 3);
-uniformable!((i32, i32, i32, i32), gl::Uniform4i, 4);
+    unsafe fn set_program_uniform(self, program: gl::types::GLuint, location: i32) {
+        gl::ProgramUniform1uiv(program, location, self.len() as i32, self.as_ptr())
+    }
+}
+
+impl Uniformable for &[i32] {
+    unsafe fn set_uniform(self, location: i32) {
+        gl::Uniform1iv(location, self.len() as i32, self.as_ptr())
+    }
+
+    unsafe fn set_program_uniform(self, program: gl::types::GLuint, location: i32) {
+        gl::ProgramUniform1iv(program, location, self.len() as i32, self.as_ptr())
+    }
+}
+
+// Array of matrices (e.g. bone palette). Nested arrays are contiguous, so slice can be passed as is
+impl Uniformable for &[[[f32; 4]; 4]] {
+    unsafe fn set_uniform(self, location: i32) {
+        gl::UniformMatrix4fv(location, self.len() as i32, gl::FALSE, self.as_ptr() as *const f32)
+    }
+
+    unsafe fn set_program_uniform(self, program: gl::types::GLuint, location: i32) {
+        gl::ProgramUniformMatrix4fv(program, location, self.len() as i32, gl::FALSE, self.as_ptr() as *const f32)
+    }
+}
+
+// Flat column-major matrices, as many math libraries store them
+macro_rules! flat_matrix_uniformable {
+    ($type:ty, $function_name:expr, $program_function_name:expr, |$value:ident| $data:expr) => {
+        impl Uniformable for $type {
+            unsafe fn set_uniform(self, location: i32) {
+                let $value = self;
+                $function_name (location, 1, gl::FALSE, $data.as_ptr())
+            }
+
+            unsafe fn set_program_uniform(self, program: gl::types::GLuint, location: i32) {
+                let $value = self;
+                $program_function_name (program, location, 1, gl::FALSE, $data.as_ptr())
+            }
+        }
+    };
+}
+
+flat_matrix_uniformable!([f32; 16], gl::UniformMatrix4fv, gl::ProgramUniformMatrix4fv, |matrix| matrix);
+flat_matrix_uniformable!([f32; 9], gl::UniformMatrix3fv, gl::ProgramUniformMatrix3fv, |matrix| matrix);
+flat_matrix_uniformable!(Mat2, gl::UniformMatrix2fv, gl::ProgramUniformMatrix2fv, |matrix| matrix.0);
 
 
 pub fn gl_get_uniform_location(program: &Program, name: &str) -> i32 {
@@ -239,4 +1392,128 @@ pub fn gl_get_uniform_location(program: &Program, name: &str) -> i32 {
         let c_str = std::ffi::CString::new(name).unwrap();
         gl::GetUniformLocation(program.id(), c_str.as_ptr())
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(log: &str) -> String {
+        let mut file = FileIncludes::new("#version 330\nfloat a;\nvoid main() {}", "main.glsl".to_owned());
+        file.insert_lines(1, "#define A", "defines.glsl".to_owned().into());
+        parse_opengl_errors(log.to_owned(), &file, &default_error_formatter)
+    }
+
+    #[test]
+    fn uniform_components_match_glsl_types() {
+        assert_eq!(uniform_components(gl::FLOAT), 1);
+        assert_eq!(uniform_components(gl::SAMPLER_2D), 1);
+        assert_eq!(uniform_components(gl::UNSIGNED_INT_VEC3), 3);
+        assert_eq!(uniform_components(gl::FLOAT_MAT2), 4);
+        assert_eq!(uniform_components(gl::FLOAT_MAT4x3), 12);
+        assert_eq!(uniform_components(gl::FLOAT_MAT4), 16);
+    }
+
+    #[test]
+    fn feedback_varyings_are_kept_until_link() {
+        let builder = ProgramBuilder::new()
+            .transform_feedback_varyings(&["out_position", "out_velocity"], gl::SEPARATE_ATTRIBS);
+
+        let varyings: Vec<_> = builder.feedback_varyings.iter().map(|name| name.to_str().unwrap()).collect();
+        assert_eq!(varyings, ["out_position", "out_velocity"]);
+        assert_eq!(builder.feedback_mode, gl::SEPARATE_ATTRIBS);
+        assert!(ProgramBuilder::new().feedback_varyings.is_empty());
+    }
+
+    #[test]
+    fn frag_data_locations_are_kept_until_link() {
+        let builder = ProgramBuilder::new()
+            .bind_frag_data_location(0, "out_albedo")
+            .bind_frag_data_location(1, "out_normal");
+
+        let locations: Vec<_> = builder.frag_data_locations.iter()
+            .map(|(index, name)| (*index, name.to_str().unwrap()))
+            .collect();
+        assert_eq!(locations, [(0, "out_albedo"), (1, "out_normal")]);
+    }
+
+    #[test]
+    fn deleted_program_is_not_used() {
+        // Methods return before any GL call, so no context is needed
+        let mut program = Program(0, PhantomData);
+        assert!(program.is_deleted());
+
+        program.use_program();
+        program.uniform("u_time", 1.0f32);
+        program.program_uniform("u_time", 1.0f32);
+        program.dispatch_compute_indirect(0);
+        program.uniform_mat4("u_view", &[[0.0; 4]; 4], false);
+        assert!(!program.uniform_checked("u_time", 1.0f32));
+        assert!(!program.is_linked());
+        assert_eq!(program.info_log(), "");
+        assert_eq!(program.location("u_time"), -1);
+        assert_eq!(program.get_uniform_f32("u_time"), None);
+        assert_eq!(program.uniform_member_offset("u_time"), None);
+        assert_eq!(program.compute_work_group_size(), None);
+        assert!(program.attached_shaders().is_empty());
+        program.delete();
+    }
+
+    #[test]
+    fn programs_are_not_separable_by_default() {
+        assert!(!ProgramBuilder::new().separable);
+        assert!(ProgramBuilder::new().separable(true).separable);
+        assert!(!ProgramBuilder::new().separable(true).separable(false).separable);
+    }
+
+    #[test]
+    fn deleted_program_has_no_location_handles() {
+        let program = Program(0, PhantomData);
+        let time = program.location_handle("u_time");
+        assert_eq!(time, Location(-1));
+        program.set(time, 1.0f32);
+    }
+
+    #[test]
+    fn stage_is_declared_by_pragma() {
+        let stage = |text: &str| stage_pragma(&FileIncludes::new(text, "shader.glsl".to_owned()));
+
+        assert_eq!(stage("#version 450\n#pragma stage vertex"), Some(gl::VERTEX_SHADER));
+        assert_eq!(stage("#pragma stage fragment"), Some(gl::FRAGMENT_SHADER));
+        assert_eq!(stage("#pragma stage geometry"), Some(gl::GEOMETRY_SHADER));
+        assert_eq!(stage("#pragma stage tess_control"), Some(gl::TESS_CONTROL_SHADER));
+        assert_eq!(stage("  #pragma   stage  tess_evaluation "), Some(gl::TESS_EVALUATION_SHADER));
+        assert_eq!(stage("#pragma stage compute"), Some(gl::COMPUTE_SHADER));
+
+        assert_eq!(stage("#pragma stage pixel"), None);
+        assert_eq!(stage("#pragma stage"), None);
+        assert_eq!(stage("#pragma stage vertex fragment"), None);
+        assert_eq!(stage("#version 450\nvoid main() {}"), None);
+    }
+
+    #[test]
+    fn vendor_error_formats_are_mapped() {
+        // Drivers count lines from 1, like the reported lines do
+        assert_eq!(parse("0(1) : error C0000: syntax error"), "File main.glsl | Line 1 | 0(1) : error C0000: syntax error\n");
+        assert_eq!(parse("0:2(5): error: `A` undeclared"), "File main.glsl included from\ndefines.glsl | Line 1 | 0:2(5): error: `A` undeclared\n");
+        assert_eq!(parse("ERROR: 0:3: 'a' : undeclared identifier"), "File main.glsl | Line 2 | ERROR: 0:3: 'a' : undeclared identifier\n");
+
+        let file = FileIncludes::new("float a;", "main.glsl".to_owned());
+        let column = parse_opengl_errors("0:1(7): error".to_owned(), &file, &|diagnostic| format!("{:?}", diagnostic.column));
+        assert_eq!(column, "Some(7)\n");
+    }
+
+    #[test]
+    fn out_of_range_lines_are_left_unmapped() {
+        // Text has 4 lines, so the 4th is the last mapped one
+        assert_eq!(parse("0(4) : error C0000: syntax error"), "File main.glsl | Line 3 | 0(4) : error C0000: syntax error\n");
+        assert_eq!(
+            parse("0(99) : error C0000: syntax error\nlink failed"),
+            "0(99) : error C0000: syntax error (line is out of the preprocessed text, so it is not mapped to the original file)\nlink failed\n"
+        );
+        assert_eq!(
+            parse("0(0) : error C0000: syntax error"),
+            "0(0) : error C0000: syntax error (line is out of the preprocessed text, so it is not mapped to the original file)\n"
+        );
+    }
+}
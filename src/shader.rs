@@ -1,9 +1,71 @@
-use std::{path::PathBuf, ffi::{CString, CStr}};
+use std::{path::PathBuf, ffi::{CString, CStr, NulError, c_void}, marker::PhantomData, sync::atomic::{AtomicPtr, Ordering}};
 
-use crate::create_whitespace_cstring;
+use crate::{create_whitespace_cstring, preprocessor::FileLoader, program::compile_includes};
 
+/// Human-readable name of shader stage: `"vertex"`, `"fragment"` etc. Unknown types are rendered as `shader(0xNNNN)`.
+pub fn shader_type_name(shader_type: gl::types::GLenum) -> String {
+    match shader_type {
+        gl::VERTEX_SHADER => "vertex".to_owned(),
+        gl::FRAGMENT_SHADER => "fragment".to_owned(),
+        gl::GEOMETRY_SHADER => "geometry".to_owned(),
+        gl::TESS_CONTROL_SHADER => "tessellation control".to_owned(),
+        gl::TESS_EVALUATION_SHADER => "tessellation evaluation".to_owned(),
+        gl::COMPUTE_SHADER => "compute".to_owned(),
+        other => format!("shader(0x{other:04X})"),
+    }
+}
+
+/// Fails for anything but vertex, fragment, geometry, tessellation and compute shader types
+fn validate_shader_type(shader_type: gl::types::GLenum) -> Result<(), String> {
+    match shader_type {
+        gl::VERTEX_SHADER | gl::FRAGMENT_SHADER | gl::GEOMETRY_SHADER |
+        gl::TESS_CONTROL_SHADER | gl::TESS_EVALUATION_SHADER | gl::COMPUTE_SHADER => Ok(()),
+        other => Err(format!("Invalid shader type: 0x{other:04X}")),
+    }
+}
 
-pub struct Shader(gl::types::GLuint);
+fn nul_error(error: NulError) -> String {
+    format!("Shader source contains NUL byte at position {}", error.nul_position())
+}
+
+// `gl` crate is generated for OpenGL 4.5, while SPIR-V support appeared in 4.6, so it is loaded separately
+const SHADER_BINARY_FORMAT_SPIR_V: gl::types::GLenum = 0x9551;
+
+type SpecializeShaderFn = extern "system" fn(
+    gl::types::GLuint,
+    *const gl::types::GLchar,
+    gl::types::GLuint,
+    *const gl::types::GLuint,
+    *const gl::types::GLuint
+);
+
+static SPECIALIZE_SHADER: AtomicPtr<c_void> = AtomicPtr::new(std::ptr::null_mut());
+
+/// Loads `glSpecializeShader`, needed for [`Shader::from_spirv`]. Pass the same function you pass to `gl::load_with`.
+pub fn load_spirv_with<F: FnMut(&'static str) -> *const c_void>(mut loadfn: F) {
+    let mut ptr = loadfn("glSpecializeShader");
+    if ptr.is_null() {
+        ptr = loadfn("glSpecializeShaderARB");
+    }
+    SPECIALIZE_SHADER.store(ptr as *mut c_void, Ordering::Relaxed);
+}
+
+fn specialize_shader_fn() -> Option<SpecializeShaderFn> {
+    // Checked first, so GL is not queried until `load_spirv_with` is called
+    let ptr = SPECIALIZE_SHADER.load(Ordering::Relaxed);
+    if ptr.is_null() {
+        return None;
+    }
+
+    let supported = crate::gl_version() >= (4, 6) || crate::gl_has_extension("GL_ARB_gl_spirv");
+    if !supported {
+        return None;
+    }
+    Some(unsafe { std::mem::transmute::<*mut c_void, SpecializeShaderFn>(ptr) })
+}
+
+/// Compiled shader object. It can only be used on the thread of its GL context, so it is neither `Send` nor `Sync`.
+pub struct Shader(gl::types::GLuint, crate::NotSend);
 
 impl Shader {
     pub fn from_file(file: PathBuf, shader_type: gl::types::GLenum) -> Result<Self, String> {
@@ -14,38 +76,118 @@ impl Shader {
         Self::from_source_str(&string, shader_type)
     }
 
+    /// Loads file through the loader with given defines and compiles it. Errors are mapped back to original files.
+    ///
+    /// ```rust,no_run
+    /// use shader_loader::{shader::Shader, preprocessor::FileLoader};
+    ///
+    /// let loader = FileLoader::new();
+    /// // `#define MAX_LIGHTS 8` and `#define USE_SHADOWS 1` go right after `#version` of lighting.frag
+    /// let shader = Shader::compile(&loader, "shaders/lighting.frag", gl::FRAGMENT_SHADER, &[("MAX_LIGHTS", "8"), ("USE_SHADOWS", "1")]);
+    /// ```
+    pub fn compile(loader: &FileLoader, path: &str, shader_type: gl::types::GLenum, defines: &[(&str, &str)]) -> Result<Self, String> {
+        let includes = loader.load_file_with_entry_defines(path, defines)?;
+        compile_includes(&includes, shader_type, loader.error_formatter())
+    }
+
     pub fn from_source_str(source: &str, shader_type: gl::types::GLenum) -> Result<Self, String> {
-        let c_string = CString::new(source).unwrap();
+        let c_string = CString::new(source).map_err(nul_error)?;
         Self::from_source(&c_string, shader_type)
     }
     
     pub fn from_source_string(source: String, shader_type: gl::types::GLenum) -> Result<Self, String> {
-        let c_string = CString::new(source).unwrap();
+        let c_string = CString::new(source).map_err(nul_error)?;
         Self::from_source(&c_string, shader_type)
     }
 
     pub fn from_source(source: &CStr, shader_type: gl::types::GLenum) -> Result<Self, String> {
-        let id = unsafe { gl::CreateShader(shader_type) };
+        validate_shader_type(shader_type)?;
+        let shader = Shader(unsafe { gl::CreateShader(shader_type) }, PhantomData);
+
+        unsafe {
+            gl::ShaderSource(shader.0, 1, &source.as_ptr(), std::ptr::null());
+            gl::CompileShader(shader.0);
+        }
+
+        shader.check_compile_status()
+    }
+
+    /// Compiles shader from several strings, which are treated as concatenated (like a prelude and the main source).
+    /// Strings are passed to `glShaderSource` as they are, without copying them into one.
+    ///
+    /// ```rust,no_run
+    /// use shader_loader::shader::Shader;
+    ///
+    /// let common = "#version 330\nuniform float u_time;\n";
+    /// let shader = Shader::from_sources(&[common, "void main() { gl_Position = vec4(u_time); }"], gl::VERTEX_SHADER).unwrap();
+    /// ```
+    pub fn from_sources(sources: &[&str], shader_type: gl::types::GLenum) -> Result<Self, String> {
+        validate_shader_type(shader_type)?;
+        let pointers: Vec<*const gl::types::GLchar> = sources.iter().map(|source| source.as_ptr() as *const _).collect();
+        let lengths: Vec<gl::types::GLint> = sources.iter().map(|source| source.len() as gl::types::GLint).collect();
+        let shader = Shader(unsafe { gl::CreateShader(shader_type) }, PhantomData);
+
+        unsafe {
+            gl::ShaderSource(shader.0, sources.len() as gl::types::GLsizei, pointers.as_ptr(), lengths.as_ptr());
+            gl::CompileShader(shader.0);
+        }
+
+        shader.check_compile_status()
+    }
+
+    /// Creates shader from SPIR-V binary. Requires OpenGL 4.6 (or `GL_ARB_gl_spirv`) and [`load_spirv_with`] to be called beforehand.
+    ///
+    /// ```rust,no_run
+    /// # fn get_proc_address(name: &str) -> *const std::ffi::c_void { unimplemented!() }
+    /// use shader_loader::{shader::{Shader, load_spirv_with}, program::ProgramBuilder};
+    ///
+    /// // Same loader function as for `gl` (e.g. of the window library)
+    /// gl::load_with(get_proc_address);
+    /// load_spirv_with(get_proc_address);
+    ///
+    /// let vertex = Shader::from_spirv(&std::fs::read("shader.vert.spv").unwrap(), gl::VERTEX_SHADER, "main").unwrap();
+    /// let fragment = Shader::from_spirv(&std::fs::read("shader.frag.spv").unwrap(), gl::FRAGMENT_SHADER, "main").unwrap();
+    /// let program = ProgramBuilder::new().attach(&vertex).attach(&fragment).link().unwrap();
+    /// ```
+    pub fn from_spirv(binary: &[u8], shader_type: gl::types::GLenum, entry_point: &str) -> Result<Self, String> {
+        let specialize_shader = specialize_shader_fn()
+            .ok_or("SPIR-V shaders are not supported: GL_ARB_gl_spirv is unavailable or `load_spirv_with` was not called")?;
+        let entry_point = CString::new(entry_point).map_err(nul_error)?;
+        validate_shader_type(shader_type)?;
+
+        let shader = Shader(unsafe { gl::CreateShader(shader_type) }, PhantomData);
+
+        unsafe {
+            gl::ShaderBinary(
+                1, &shader.0,
+                SHADER_BINARY_FORMAT_SPIR_V,
+                binary.as_ptr() as *const std::ffi::c_void,
+                binary.len() as i32
+            );
+            specialize_shader(shader.0, entry_point.as_ptr(), 0, std::ptr::null(), std::ptr::null());
+        }
+
+        shader.check_compile_status()
+    }
 
+    fn check_compile_status(self) -> Result<Self, String> {
         //Проверка на успешную компиляцию
         let mut success: gl::types::GLint = 1;
         unsafe {
-            gl::ShaderSource(id, 1, &source.as_ptr(), std::ptr::null());
-            gl::CompileShader(id);
-            gl::GetShaderiv(id, gl::COMPILE_STATUS, &mut success);
+            gl::GetShaderiv(self.0, gl::COMPILE_STATUS, &mut success);
         }
 
         if success == 0 {
             //Получение длины текста ошибки и самого текста
             let mut len: gl::types::GLint = 0;
             unsafe {
-                gl::GetShaderiv(id, gl::INFO_LOG_LENGTH, &mut len);
+                gl::GetShaderiv(self.0, gl::INFO_LOG_LENGTH, &mut len);
             }
 
             let error: CString = create_whitespace_cstring(len as usize);
 
             unsafe {
-                gl::GetShaderInfoLog(id, len, std::ptr::null_mut(),
+                gl::GetShaderInfoLog(self.0, len, std::ptr::null_mut(),
                     error.as_ptr() as *mut gl::types::GLchar);
             }
             let error = error.to_string_lossy()
@@ -56,7 +198,7 @@ impl Shader {
             return Err(error);
         } 
 
-        Ok(Shader(id))
+        Ok(self)
     }
 
     pub fn id(&self) -> gl::types::GLuint {
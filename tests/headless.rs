@@ -0,0 +1,931 @@
+//! Preprocessor and `Path` tests, which need no GL context.
+//! Run them on machines without GPU with `cargo test --no-default-features` (plus `--features serde,net` for JSON export and HTTP loading).
+//! Tests under `gl` feature only check failures, which are detected before any GL call.
+
+use std::{cell::{Cell, RefCell}, path::PathBuf, rc::Rc};
+
+use shader_loader::{Path, preprocessor::{Diagnostic, EmbeddedFs, FileIncludes, FileLoader, PreprocessOptions, TraceEvent, default_error_formatter}};
+
+fn loader(files: &[(&'static str, &'static str)]) -> FileLoader {
+    let mut loader = FileLoader::new();
+    loader.add_protocol("mem".to_owned(), EmbeddedFs::from_files(files).into_protocol()).unwrap();
+    loader
+}
+
+#[test]
+fn includes_are_expanded_once() {
+    let loader = loader(&[
+        ("main.glsl", "#version 330\n#include_once \"a.glsl\"\n#include_once \"b.glsl\"\nvoid main() {}"),
+        ("a.glsl", "#include_once \"common.glsl\"\nfloat a;"),
+        ("b.glsl", "#include_once \"common.glsl\"\nfloat b;"),
+        ("common.glsl", "float common;"),
+    ]);
+
+    let file = loader.load_file("mem://main.glsl").unwrap();
+    assert_eq!(file.text(), "#version 330\nfloat common;\nfloat a;\n\nfloat b;\nvoid main() {}");
+    assert!(file.check_invariants().is_ok());
+
+    let mut used_files = file.all_used_files();
+    used_files.sort();
+    assert_eq!(used_files, ["mem://a.glsl", "mem://b.glsl", "mem://common.glsl", "mem://main.glsl"]);
+}
+
+#[test]
+fn lines_are_mapped_to_original_files() {
+    let loader = loader(&[
+        ("main.glsl", "#version 330\n#include_once \"lib/a.glsl\"\nvoid main() {}"),
+        ("lib/a.glsl", "float a;\n#include_once \"b.glsl\"\nfloat a2;"),
+        ("lib/b.glsl", "float b;"),
+    ]);
+
+    let file = loader.load_file("mem://main.glsl").unwrap();
+    let mapped: Vec<_> = (0..5)
+        .map(|line| file.file_and_line_at(line).map(|(file, line)| (file.to_string(), line)))
+        .collect();
+
+    assert_eq!(mapped, [
+        Some(("mem://main.glsl".to_owned(), 0)),
+        Some(("mem://lib/a.glsl".to_owned(), 0)),
+        Some(("mem://lib/b.glsl".to_owned(), 0)),
+        Some(("mem://lib/a.glsl".to_owned(), 2)),
+        Some(("mem://main.glsl".to_owned(), 2)),
+    ]);
+}
+
+#[test]
+fn errors_point_to_the_include_directive() {
+    let loader = loader(&[("main.glsl", "#version 330\n#include_once \"missing.glsl\"")]);
+
+    let error = loader.load_file("mem://main.glsl").unwrap_err();
+    assert!(error.contains("missing.glsl"), "{error}");
+    assert!(error.contains("included from mem://main.glsl | Line 2"), "{error}");
+}
+
+#[test]
+fn include_limit_is_respected() {
+    let mut loader = loader(&[
+        ("main.glsl", "#include_once \"a.glsl\"\n#include_once \"b.glsl\""),
+        ("a.glsl", "float a;"),
+        ("b.glsl", "float b;"),
+    ]);
+
+    loader.max_total_includes(Some(1));
+    assert!(loader.load_file("mem://main.glsl").is_err());
+
+    loader.max_total_includes(Some(2));
+    assert!(loader.load_file("mem://main.glsl").is_ok());
+}
+
+#[test]
+fn options_are_applied() {
+    let loads = Rc::new(Cell::new(0));
+    let events = Rc::new(RefCell::new(vec![]));
+    let recorded = events.clone();
+    let options = PreprocessOptions::new()
+        .search_dir("mem://lib")
+        .alias("@engine", "mem://engine")
+        .hoist_extensions(true)
+        .strip_suppressed_lines(true)
+        .cache_expansions(true)
+        .max_total_includes(Some(2))
+        .trace(move |event| recorded.borrow_mut().push(event.clone()))
+        .error_formatter(|diagnostic| format!("{}: {}", diagnostic.line.unwrap(), diagnostic.message));
+
+    let files = EmbeddedFs::from_files(&[
+        ("main.glsl", "#version 330\n#include_once <util.glsl>\n#include_once \"@engine/light.glsl\"\n#include_once <util.glsl>\nvoid main() {}"),
+        ("lib/util.glsl", "#extension GL_ARB_shading_language_include : enable\nfloat util;"),
+        ("engine/light.glsl", "float light;"),
+    ]);
+    let with_protocol = |options: PreprocessOptions| {
+        let (loads, files) = (loads.clone(), files.clone());
+        let mut loader = FileLoader::with_options(options);
+        loader.add_protocol("mem".to_owned(), move |path: &str| {
+            loads.set(loads.get() + 1);
+            files.get(path).map(str::to_owned).ok_or(format!("No file {path}"))
+        }).unwrap();
+        loader
+    };
+    let loader = with_protocol(options.clone());
+
+    // Search dir, alias, hoisting and stripping
+    let file = loader.load_file("mem://main.glsl").unwrap();
+    assert_eq!(file.text(), "#version 330\n#extension GL_ARB_shading_language_include : enable\n\nfloat util;\nfloat light;\nvoid main() {}");
+
+    // Cache: nothing is loaded again
+    let first_loads = loads.get();
+    loader.load_file("mem://main.glsl").unwrap();
+    assert_eq!(loads.get(), first_loads);
+
+    // Trace
+    assert_eq!(events.borrow().first(), Some(&TraceEvent::Loading("mem://main.glsl".to_owned())));
+    assert!(events.borrow().contains(&TraceEvent::Loading("mem://engine/light.glsl".to_owned())));
+
+    // Error formatter
+    let diagnostic = Diagnostic { message: "error".to_owned(), file: None, line: Some(2), column: None, included_from: vec![] };
+    assert_eq!((loader.error_formatter())(&diagnostic), "2: error");
+
+    // Includes limit: 2 files are included, so the limit of 1 is exceeded on light.glsl
+    let error = with_protocol(options.max_total_includes(Some(1))).load_file("mem://main.glsl").err().unwrap();
+    assert!(error.starts_with("Too many included files, the limit is 1") && error.contains("@engine/light.glsl"), "{error}");
+}
+
+#[test]
+fn paths_are_normalized() {
+    assert_eq!(Path::new("a/b/../c").to_string(), "a/c");
+    assert_eq!(Path::new("res://dir/a.glsl").dirname().join("../b.glsl").to_string(), "res://b.glsl");
+    assert_eq!(Path::new("res://dir").join("file://abs").to_string(), "file://abs");
+    assert_eq!(Path::new("RES://a/b/"), Path::new("res://a/b"));
+}
+
+#[test]
+fn relative_includes_keep_parent_protocol() {
+    // There are no such files on disk, so they can only be loaded via `mem://`
+    let loader = loader(&[
+        ("dir/main.glsl", "#include_once \"child.glsl\"\n#include_once \"../lib/util.glsl\""),
+        ("dir/child.glsl", "float child;"),
+        ("lib/util.glsl", "#include_once \"nested/deep.glsl\"\nfloat util;"),
+        ("lib/nested/deep.glsl", "float deep;"),
+    ]);
+
+    let file = loader.load_file("mem://dir/main.glsl").unwrap();
+    assert_eq!(file.text(), "float child;\nfloat deep;\nfloat util;");
+
+    let mut used_files = file.all_used_files();
+    used_files.sort();
+    assert_eq!(used_files, ["mem://dir/child.glsl", "mem://dir/main.glsl", "mem://lib/nested/deep.glsl", "mem://lib/util.glsl"]);
+}
+
+#[test]
+fn paths_convert_to_and_from_path_buf() {
+    let path = Path::from(PathBuf::from("shaders/lib/util.glsl"));
+    assert_eq!(path, Path::new("shaders/lib/util.glsl"));
+    assert_eq!(PathBuf::try_from(&path), Ok(PathBuf::from("shaders/lib/util.glsl")));
+
+    assert_eq!(PathBuf::try_from(&Path::new("file:///usr/share")), Ok(PathBuf::from("/usr/share")));
+    assert!(PathBuf::try_from(&Path::new("res://shaders/a.glsl")).is_err());
+}
+
+#[test]
+fn angled_includes_are_searched_only_in_search_dirs() {
+    let mut with_search_dir = loader(&[
+        ("dir/main.glsl", "#include_once <util.glsl>\n#include_once \"local.glsl\""),
+        ("dir/util.glsl", "float dir_util;"),
+        ("dir/local.glsl", "float local;"),
+        ("lib/util.glsl", "float lib_util;"),
+    ]);
+    with_search_dir.add_search_dir("mem://lib");
+
+    let file = with_search_dir.load_file("mem://dir/main.glsl").unwrap();
+    assert_eq!(file.text(), "float lib_util;\nfloat local;");
+
+    let without_search_dirs = loader(&[("main.glsl", "#include_once <local.glsl>"), ("local.glsl", "float local;")]);
+    let error = without_search_dirs.load_file("mem://main.glsl").unwrap_err();
+    assert!(error.contains("File <local.glsl> is not found in search directories"), "{error}");
+}
+
+#[test]
+fn joining_absolute_paths_replaces_base() {
+    assert_eq!(Path::new("res://dir").join("file://abs/a.glsl").to_string(), "file://abs/a.glsl");
+    assert_eq!(Path::new("a/b").join("/c").to_string(), "/c");
+    assert_eq!(Path::new("res://a/b").join("/c").to_string(), "res://c");
+    assert_eq!(Path::new("res://a").join("b/c").to_string(), "res://a/b/c");
+
+    assert!(Path::new("res://a").try_join("file://b").is_err());
+    assert_eq!(Path::new("res://a").try_join("b"), Ok(Path::new("res://a/b")));
+}
+
+#[test]
+fn prelude_keeps_mapping_of_following_lines() {
+    let loader = loader(&[("main.glsl", "#version 330\nfloat a;\nvoid main() {}")]);
+
+    let mut file = loader.load_file("mem://main.glsl").unwrap();
+    file.insert_after_version("#define A 1\n#define B 2", "<prelude>".to_owned().into());
+
+    assert_eq!(file.text(), "#version 330\n#define A 1\n#define B 2\nfloat a;\nvoid main() {}");
+    assert_eq!(file.file_and_line_at(0), Some(("mem://main.glsl".to_owned().into(), 0)));
+    assert_eq!(file.file_and_line_at(2), Some(("<prelude>".to_owned().into(), 1)));
+    assert_eq!(file.file_and_line_at(4), Some(("mem://main.glsl".to_owned().into(), 2)));
+}
+
+#[test]
+fn cloned_loaders_share_protocols_but_not_new_ones() {
+    let original = loader(&[("main.glsl", "float main;")]);
+    let mut clone = original.clone();
+    clone.add_protocol("extra".to_owned(), EmbeddedFs::from_files(&[("a.glsl", "float a;")]).into_protocol()).unwrap();
+
+    assert_eq!(clone.load_file("mem://main.glsl").unwrap().text(), "float main;");
+    assert_eq!(clone.load_file("extra://a.glsl").unwrap().text(), "float a;");
+    assert!(original.get_protocol("extra").is_none());
+}
+
+#[test]
+fn lines_of_a_file_are_extracted() {
+    let loader = loader(&[
+        ("main.glsl", "#version 330\n#include_once \"a.glsl\"\nvoid main() {}"),
+        ("a.glsl", "float a;\nfloat a2;"),
+    ]);
+
+    let file = loader.load_file("mem://main.glsl").unwrap();
+    assert_eq!(file.lines_from_file("mem://a.glsl"), [(1, "float a;"), (2, "float a2;")]);
+    assert_eq!(file.lines_from_file("mem://main.glsl"), [(0, "#version 330"), (3, "void main() {}")]);
+    assert!(file.lines_from_file("mem://missing.glsl").is_empty());
+
+    // Each unit includes its own copy of a.glsl (`include_once` works within one load)
+    let units = FileIncludes::concat(vec![file.clone(), loader.load_file("mem://main.glsl").unwrap()], "// next unit");
+    assert_eq!(units.lines_from_file("mem://a.glsl"), [(1, "float a;"), (2, "float a2;"), (6, "float a;"), (7, "float a2;")]);
+}
+
+#[test]
+fn errors_list_the_whole_include_chain() {
+    let loader = loader(&[
+        ("main.glsl", "#version 330\n\n#include_once   \"a.glsl\""),
+        ("a.glsl", "#pragma include_once <missing.glsl>"),
+    ]);
+
+    let error = loader.load_file("mem://main.glsl").unwrap_err();
+    let chain: Vec<_> = error.lines().skip(1).collect();
+    assert_eq!(chain, [
+        "    included from mem://a.glsl | Line 1 | #pragma include_once <missing.glsl>",
+        "    included from mem://main.glsl | Line 3 | #include_once   \"a.glsl\"",
+    ]);
+}
+
+#[test]
+fn debug_dump_shows_origin_of_every_line() {
+    let loader = loader(&[
+        ("main.glsl", "#version 330\n#include_once \"a.glsl\"\nvoid main() {}"),
+        ("a.glsl", "float a;"),
+    ]);
+
+    let dump = loader.load_file("mem://main.glsl").unwrap().debug_dump();
+    assert_eq!(dump, "\
+Lines:
+    0 | mem://main.glsl:0 | #version 330
+    1 | mem://a.glsl:0 | float a;
+    2 | mem://main.glsl:2 | void main() {}
+Segments:
+    [0, 3) mem://main.glsl (replaces 0 lines)
+    [1, 2) mem://a.glsl (replaces 1 lines)
+");
+}
+
+#[test]
+fn custom_canonicalizer_deduplicates_includes() {
+    let files = [
+        ("main.glsl", "#include_once \"Common.glsl\"\n#include_once \"common.glsl\""),
+        ("Common.glsl", "float common;"),
+        ("common.glsl", "float common;"),
+    ];
+
+    let plain = loader(&files);
+    assert_eq!(plain.load_file("mem://main.glsl").unwrap().text(), "float common;\nfloat common;");
+
+    // Case-insensitive file system
+    let mut case_insensitive = loader(&files);
+    case_insensitive.set_canonicalizer("mem", |path| Ok(path.to_lowercase()));
+    assert_eq!(case_insensitive.canonical_key("mem://Common.glsl"), "mem://common.glsl");
+    assert_eq!(case_insensitive.load_file("mem://main.glsl").unwrap().text(), "float common;\n");
+}
+
+#[test]
+fn entry_defines_are_inserted_after_version() {
+    let loader = loader(&[("main.glsl", "#version 450 core\nvoid main() {}")]);
+
+    let file = loader.load_file_with_entry_defines("mem://main.glsl", &[("SHADOWS", "1"), ("LIGHTS", "4")]).unwrap();
+    assert_eq!(file.text(), "#version 450 core\n#define SHADOWS 1\n#define LIGHTS 4\nvoid main() {}");
+    assert_eq!(file.file_and_line_at(2), Some(("<defines>".to_owned().into(), 1)));
+    assert_eq!(file.file_and_line_at(3), Some(("mem://main.glsl".to_owned().into(), 1)));
+
+    // Defines affect only that load
+    assert_eq!(loader.load_file("mem://main.glsl").unwrap().text(), "#version 450 core\nvoid main() {}");
+}
+
+#[test]
+fn paths_are_made_from_strings_and_paths() {
+    fn dirname(path: impl Into<Path>) -> Path {
+        path.into().dirname()
+    }
+
+    let string = "res://a/b.glsl".to_owned();
+    let path = Path::new("res://a/b.glsl");
+    assert_eq!(dirname(&string), Path::new("res://a"));
+    assert_eq!(dirname(&path), Path::new("res://a"));
+    assert_eq!(dirname(string), dirname("res://a/b.glsl"));
+}
+
+#[test]
+fn sources_from_strings_include_files_relative_to_virtual_path() {
+    let loader = loader(&[("shaders/lib.glsl", "float lib;")]);
+
+    let file = loader.preprocess_source("#version 330\n#include_once \"lib.glsl\"\nvoid main() {}", "mem://shaders/generated.glsl").unwrap();
+    assert_eq!(file.text(), "#version 330\nfloat lib;\nvoid main() {}");
+    assert_eq!(file.file_and_line_at(2), Some(("mem://shaders/generated.glsl".to_owned().into(), 2)));
+}
+
+#[test]
+fn diagnostics_are_formatted() {
+    let diagnostic = Diagnostic {
+        message: "error: `x` undeclared".to_owned(),
+        file: Some("mem://b.glsl".to_owned().into()),
+        line: Some(3),
+        column: Some(7),
+        included_from: vec!["mem://main.glsl".to_owned().into(), "mem://a.glsl".to_owned().into()],
+    };
+    assert_eq!(
+        default_error_formatter(&diagnostic),
+        "File mem://main.glsl included from\nmem://a.glsl included from\nmem://b.glsl | Line 3 | error: `x` undeclared"
+    );
+
+    let unmapped = Diagnostic { file: None, line: None, ..diagnostic.clone() };
+    assert_eq!(default_error_formatter(&unmapped), "error: `x` undeclared");
+
+    let loader = FileLoader::with_options(PreprocessOptions::new().error_formatter(|diagnostic| {
+        format!("{}:{}: {}", diagnostic.file.as_deref().unwrap(), diagnostic.line.unwrap(), diagnostic.message)
+    }));
+    assert_eq!((loader.error_formatter())(&diagnostic), "mem://b.glsl:3: error: `x` undeclared");
+}
+
+#[test]
+fn commented_out_includes_are_skipped() {
+    let loader = loader(&[
+        ("main.glsl", "// #include_once \"a.glsl\"\n/*\n#include_once \"a.glsl\"\n*/\n#include_once/**/\"b.glsl\""),
+        ("b.glsl", "float b;"),
+    ]);
+
+    let file = loader.load_file("mem://main.glsl").unwrap();
+    assert_eq!(file.text(), "// #include_once \"a.glsl\"\n/*\n#include_once \"a.glsl\"\n*/\nfloat b;");
+}
+
+#[test]
+fn file_urls_are_percent_decoded() {
+    assert_eq!(Path::new("file:///my%20shaders/a%2Bb.glsl").decoded().to_string(), "file:///my shaders/a+b.glsl");
+    assert_eq!(Path::new("my%20shaders/a.glsl").decoded().to_string(), "my%20shaders/a.glsl");
+    // Decoded once, so `%2525` is `%25`, not `%`
+    assert_eq!(Path::new("file:///100%25.glsl").decoded().to_string(), "file:///100%.glsl");
+    assert_eq!(Path::new("file:///100%2525.glsl").decoded().to_string(), "file:///100%25.glsl");
+
+    let dir = std::env::temp_dir().join("shader_loader percent test");
+    std::fs::create_dir_all(&dir).unwrap();
+    std::fs::write(dir.join("a.glsl"), "float a;").unwrap();
+
+    let plain = format!("file://{}/a.glsl", dir.display());
+    let encoded = plain.replace(' ', "%20");
+    let loader = FileLoader::new();
+    assert_eq!(loader.load_file(&encoded).unwrap().text(), "float a;");
+    assert_eq!(loader.canonical_key(&encoded), loader.canonical_key(&plain));
+}
+
+#[test]
+fn include_filenames_can_contain_spaces() {
+    let mut loader = loader(&[
+        ("main.glsl", "#include_once \"my lib/a b.glsl\"\n#include_once <c (1).glsl>\n#include_once   d.glsl  "),
+        ("my lib/a b.glsl", "float ab;"),
+        ("lib/c (1).glsl", "float c;"),
+        ("d.glsl", "float d;"),
+    ]);
+    loader.add_search_dir("mem://lib");
+
+    let file = loader.load_file("mem://main.glsl").unwrap();
+    assert_eq!(file.text(), "float ab;\nfloat c;\nfloat d;");
+}
+
+#[test]
+fn appended_source_is_mapped_to_virtual_file() {
+    let loader = loader(&[("main.glsl", "#version 330\nvoid main() {}")]);
+
+    let mut file = loader.load_file("mem://main.glsl").unwrap();
+    file.append_source("void helper() {}\nvoid helper2() {}", "<generated>");
+
+    assert_eq!(file.text(), "#version 330\nvoid main() {}\nvoid helper() {}\nvoid helper2() {}");
+    assert_eq!(file.file_and_line_at(1), Some(("mem://main.glsl".to_owned().into(), 1)));
+    assert_eq!(file.file_and_line_at(3), Some(("<generated>".to_owned().into(), 1)));
+}
+
+#[test]
+fn paths_equality_ignores_trailing_slash_and_protocol_case() {
+    use std::collections::HashSet;
+
+    assert_eq!(Path::new("RES://a/b/"), Path::new("res://a/b"));
+    assert_eq!(Path::new("a\\b"), Path::new("a/b"));
+    assert_ne!(Path::new("res://A/b"), Path::new("res://a/b"));
+    assert_ne!(Path::new("res://a/b"), Path::new("a/b"));
+
+    let set: HashSet<Path> = ["File://x/y/", "file://x/y", "FILE://x//y"].into_iter().map(Path::new).collect();
+    assert_eq!(set.len(), 1);
+}
+
+#[test]
+fn dependencies_are_listed_without_compiling() {
+    let loader = loader(&[
+        ("main.glsl", "#include_once \"b.glsl\"\n#include_once \"a.glsl\""),
+        ("a.glsl", "#include_once \"b.glsl\"\nfloat a;"),
+        ("b.glsl", "float b;"),
+    ]);
+
+    assert_eq!(loader.dependencies("mem://main.glsl").unwrap(), ["mem://a.glsl", "mem://b.glsl", "mem://main.glsl"]);
+    assert!(loader.dependencies("mem://missing.glsl").is_err());
+}
+
+#[test]
+fn include_resolution_is_traced() {
+    use std::{cell::RefCell, rc::Rc};
+
+    let events = Rc::new(RefCell::new(vec![]));
+    let recorder = events.clone();
+    let mut loader = loader(&[
+        ("main.glsl", "#include_once \"a.glsl\"\n#include_once \"a.glsl\"\n#include_once \"missing.glsl\""),
+        ("a.glsl", "float a;"),
+    ]);
+    loader.set_trace(Box::new(move |event| recorder.borrow_mut().push(event.clone())));
+
+    assert!(loader.load_file("mem://main.glsl").is_err());
+    assert_eq!(*events.borrow(), [
+        TraceEvent::Loading("mem://main.glsl".to_owned()),
+        TraceEvent::Included { parent: "mem://main.glsl".to_owned(), child: "mem://a.glsl".to_owned() },
+        TraceEvent::Loading("mem://a.glsl".to_owned()),
+        TraceEvent::Suppressed { path: "mem://a.glsl".to_owned(), reason: "already included".to_owned() },
+        TraceEvent::Included { parent: "mem://main.glsl".to_owned(), child: "mem://missing.glsl".to_owned() },
+        TraceEvent::Loading("mem://missing.glsl".to_owned()),
+        TraceEvent::Failed { path: "mem://missing.glsl".to_owned(), error: "Embedded file does not exist: missing.glsl".to_owned() },
+    ]);
+}
+
+#[test]
+fn suppressed_includes_can_be_stripped() {
+    let files = [
+        ("main.glsl", "#include_once \"a.glsl\"\n#include_once \"a.glsl\"\nvoid main() {}"),
+        ("a.glsl", "float a;"),
+    ];
+
+    let blanked = loader(&files).load_file("mem://main.glsl").unwrap();
+    assert_eq!(blanked.text(), "float a;\n\nvoid main() {}");
+
+    let mut loader = loader(&files);
+    loader.strip_suppressed_lines(true);
+    let stripped = loader.load_file("mem://main.glsl").unwrap();
+    assert_eq!(stripped.text(), "float a;\nvoid main() {}");
+    assert_eq!(stripped.file_and_line_at(1), Some(("mem://main.glsl".to_owned().into(), 2)));
+    assert!(stripped.check_invariants().is_ok());
+}
+
+#[test]
+fn file_included_after_stripped_include_is_mapped() {
+    let files = [
+        ("f0.glsl", "#include_once \"f3.glsl\"\n#include_once \"f2.glsl\"\nvoid main() {}"),
+        // The second include of f4 is the last line of f3, so its empty segment is where f2 starts
+        ("f3.glsl", "float f3;\n#include_once \"f4.glsl\"\nfloat f3_end;\n#include_once \"f4.glsl\""),
+        ("f4.glsl", "float f4;"),
+        ("f2.glsl", "float f2;\nfloat f2_end;"),
+    ];
+
+    let mut loader = loader(&files);
+    loader.strip_suppressed_lines(true);
+    let file = loader.load_file("mem://f0.glsl").unwrap();
+
+    assert_eq!(file.text(), "float f3;\nfloat f4;\nfloat f3_end;\nfloat f2;\nfloat f2_end;\nvoid main() {}");
+    assert_eq!(file.file_and_line_at(2), Some(("mem://f3.glsl".to_owned().into(), 2)));
+    assert_eq!(file.file_and_line_at(3), Some(("mem://f2.glsl".to_owned().into(), 0)));
+    assert_eq!(file.file_and_line_at(4), Some(("mem://f2.glsl".to_owned().into(), 1)));
+    assert_eq!(file.file_and_line_at(5), Some(("mem://f0.glsl".to_owned().into(), 2)));
+    assert!(file.check_invariants().is_ok());
+}
+
+#[test]
+fn extensions_are_hoisted_once() {
+    let loader = loader(&[
+        ("main.glsl", "#version 450 core\n#include_once \"a.glsl\"\n#include_once \"b.glsl\"\nvoid main() {}"),
+        ("a.glsl", "float a;\n#extension GL_EXT_a : enable"),
+        ("b.glsl", "#extension  GL_EXT_a : enable\n#extension GL_EXT_b : require"),
+    ]);
+
+    let mut file = loader.load_file("mem://main.glsl").unwrap();
+    file.hoist_extensions();
+
+    assert_eq!(file.detected_version(), Some((450, Some("core".to_owned()))));
+    assert_eq!(file.text(), "#version 450 core\n#extension GL_EXT_a : enable\n#extension GL_EXT_b : require\nfloat a;\n\n\n\nvoid main() {}");
+    assert_eq!(file.file_and_line_at(1), Some(("mem://a.glsl".to_owned().into(), 1)));
+    assert_eq!(file.file_and_line_at(2), Some(("mem://b.glsl".to_owned().into(), 1)));
+    assert_eq!(file.file_and_line_at(7), Some(("mem://main.glsl".to_owned().into(), 3)));
+    assert!(file.check_invariants().is_ok());
+}
+
+#[test]
+fn canonical_string_differs_from_display() {
+    assert_eq!(Path::new("shaders/a.glsl").canonical_string(), "file://shaders/a.glsl");
+    assert_eq!(Path::new("/usr/a.glsl").canonical_string(), "file:///usr/a.glsl");
+    assert_eq!(Path::new("RES://my%20dir/./a.glsl?v=1").canonical_string(), "res://my dir/a.glsl?v=1");
+    assert_eq!(Path::new("RES://my%20dir/./a.glsl?v=1").to_string(), "res://my%20dir/a.glsl?v=1");
+}
+
+#[test]
+fn units_are_concatenated_with_separator() {
+    let loader = loader(&[
+        ("a.glsl", "#include_once \"common.glsl\"\nfloat a;"),
+        ("b.glsl", "float b;"),
+        ("common.glsl", "float common;"),
+    ]);
+    let units = vec![loader.load_file("mem://a.glsl").unwrap(), loader.load_file("mem://b.glsl").unwrap()];
+
+    let merged = FileIncludes::concat(units, "// ---");
+    assert_eq!(merged.text(), "float common;\nfloat a;\n// ---\nfloat b;");
+    assert_eq!(merged.file_and_line_at(0), Some(("mem://common.glsl".to_owned().into(), 0)));
+    assert_eq!(merged.file_and_line_at(1), Some(("mem://a.glsl".to_owned().into(), 1)));
+    assert_eq!(merged.file_and_line_at(2), Some(("<concat>".to_owned().into(), 0)));
+    assert_eq!(merged.file_and_line_at(3), Some(("mem://b.glsl".to_owned().into(), 0)));
+    assert!(merged.check_invariants().is_ok());
+}
+
+#[test]
+fn manual_include_guards_are_reported() {
+    let loader = loader(&[
+        ("main.glsl", "#include_once \"guarded.glsl\"\n#include_once \"plain.glsl\""),
+        ("guarded.glsl", "// Lighting\n#ifndef LIGHTING_GLSL\n#define LIGHTING_GLSL\nfloat light;\n#endif"),
+        ("plain.glsl", "#ifndef USE_FOG\n#define FOG 0\n#endif"),
+    ]);
+
+    assert_eq!(loader.include_guard_warnings("mem://main.glsl").unwrap(), [
+        "File mem://guarded.glsl is included with #include_once, but also has a manual include guard LIGHTING_GLSL",
+    ]);
+}
+
+#[test]
+fn paths_are_split_by_custom_separators() {
+    let path = Path::with_separators("res://com.example.shader", &['.']);
+    assert_eq!(path.to_string(), "res://com/example/shader");
+    assert_eq!(path, Path::new("res://com/example/shader"));
+    assert_eq!(Path::with_separators("a/b.c", &['.']).into_iter().collect::<Vec<_>>(), ["a/b", "c"]);
+}
+
+#[test]
+fn path_prefixes_are_matched_by_components() {
+    let path = Path::new("res://shaders/lib/util.glsl?v=2");
+
+    assert!(path.starts_with(&Path::new("res://shaders")));
+    assert!(!path.starts_with(&Path::new("res://sha")));
+    assert!(!path.starts_with(&Path::new("file://shaders")));
+
+    let rest = path.strip_prefix(&Path::new("res://shaders/")).unwrap();
+    assert_eq!(rest.to_string(), "lib/util.glsl?v=2");
+    assert_eq!(path.strip_prefix(&Path::new("res://lib")), None);
+}
+
+#[test]
+fn file_protocol_errors_tell_missing_files_apart() {
+    let missing = std::env::temp_dir().join("shader_loader missing test/a.glsl");
+    let error = FileLoader::new().load_file(&missing.to_string_lossy()).unwrap_err();
+    assert_eq!(error, format!("File {} is not found", missing.display()));
+}
+
+#[test]
+fn included_regions_are_wrapped_in_guards() {
+    let loader = loader(&[
+        ("main.glsl", "#version 330\n#include_once \"a.glsl\"\nvoid main() {}"),
+        ("a.glsl", "float a;\nfloat a2;"),
+    ]);
+
+    let text = loader.load_file("mem://main.glsl").unwrap().text_with_guards();
+    let lines: Vec<_> = text.lines().collect();
+    let guard = lines[1].strip_prefix("#ifndef ").unwrap();
+
+    assert!(guard.starts_with("__FILE_"), "{text}");
+    assert_eq!(lines, ["#version 330", lines[1], &format!("#define {guard}"), "float a;", "float a2;", "#endif", "void main() {}"]);
+    assert_eq!(text, loader.load_file("mem://main.glsl").unwrap().text_with_guards());
+
+    // Replaced lines are not included files, so they are not guarded
+    let mut file = loader.load_file("mem://main.glsl").unwrap();
+    file.replace_line_with(3, "void main() { discard; }", "<patch>".to_owned().into());
+    let lines: Vec<_> = file.text_with_guards().lines().map(str::to_owned).collect();
+    assert_eq!(lines.last().map(String::as_str), Some("void main() { discard; }"));
+    assert_eq!(lines.iter().filter(|line| line.starts_with("#ifndef")).count(), 1);
+}
+
+#[test]
+fn queries_are_passed_to_protocols() {
+    let mut loader = FileLoader::new();
+    loader.add_protocol("gen".to_owned(), |path: &str| match path {
+        "shaders/main.glsl?quality=high" => Ok("#include_once \"noise?octaves=4\"".to_owned()),
+        path => Ok(format!("// generated {path}")),
+    }).unwrap();
+
+    let file = loader.load_file("gen://shaders/main.glsl?quality=high").unwrap();
+    assert_eq!(file.text(), "// generated shaders/noise?octaves=4");
+
+    let path = Path::new("gen://shaders/noise?octaves=4");
+    assert_eq!(path.query(), Some("octaves=4"));
+    assert_eq!(path.into_iter().collect::<Vec<_>>(), ["shaders", "noise"]);
+    assert_eq!(path.dirname().to_string(), "gen://shaders");
+}
+
+#[test]
+fn parent_directories_are_resolved_across_protocols() {
+    let loader = loader(&[
+        ("shaders/post/blur.glsl", "#include_once \"../../common/math.glsl\"\n#include_once \"../../../../common/limits.glsl\""),
+        ("common/math.glsl", "float math;"),
+        ("common/limits.glsl", "float limits;"),
+    ]);
+
+    // There is nothing above the root of a protocol, so extra `..` stop there
+    let file = loader.load_file("mem://shaders/post/blur.glsl").unwrap();
+    assert_eq!(file.text(), "float math;\nfloat limits;");
+
+    assert_eq!(Path::new("../../a").join("../b").to_string(), "../../b");
+    assert_eq!(Path::new("../a").join("../../b").to_string(), "../../b");
+    assert_eq!(Path::new("/a").join("../../b").to_string(), "/b");
+}
+
+#[cfg(unix)]
+#[test]
+fn symlink_cycles_are_included_once() {
+    let dir = std::env::temp_dir().join("shader_loader symlink test");
+    std::fs::create_dir_all(&dir).unwrap();
+    let link = dir.join("link");
+    if !link.exists() {
+        std::os::unix::fs::symlink(&dir, &link).unwrap();
+    }
+    let main = dir.join("main.glsl");
+    std::fs::write(&main, format!("#include_once \"link/link/main.glsl\"\n#include_once \"{}\"\nfloat main;", main.display())).unwrap();
+
+    let file = FileLoader::new().load_file(&main.to_string_lossy()).unwrap();
+    assert_eq!(file.text(), "\n\nfloat main;");
+}
+
+#[test]
+fn several_components_are_joined_at_once() {
+    assert_eq!(Path::new("res://a").join_all(&["b", "../c", "d.glsl"]).to_string(), "res://a/c/d.glsl");
+    assert_eq!(Path::from_components(Some("RES"), &["a", "b"]), Path::new("res://a/b"));
+    assert_eq!(Path::from_components(None, &["a", "b/c"]).to_string(), "a/b/c");
+}
+
+#[test]
+fn include_limit_error_points_to_the_extra_include() {
+    let mut loader = FileLoader::with_options(PreprocessOptions::new().max_total_includes(Some(2)));
+    loader.add_protocol("mem".to_owned(), EmbeddedFs::from_files(&[
+        ("main.glsl", "#include_once \"a.glsl\""),
+        ("a.glsl", "#include_once \"b.glsl\"\n#include_once \"c.glsl\""),
+        ("b.glsl", "float b;"),
+        ("c.glsl", "float c;"),
+    ]).into_protocol()).unwrap();
+
+    let error = loader.load_file("mem://main.glsl").unwrap_err();
+    assert_eq!(error, "Too many included files, the limit is 2\
+        \n    included from mem://a.glsl | Line 2 | #include_once \"c.glsl\"\
+        \n    included from mem://main.glsl | Line 1 | #include_once \"a.glsl\"");
+}
+
+#[test]
+fn aliases_are_applied_before_other_resolution() {
+    let mut loader = FileLoader::with_options(PreprocessOptions::new()
+        .alias("@engine", "mem://engine")
+        .alias("@engine/lighting", "mem://overrides/lighting.glsl"));
+    loader.add_protocol("mem".to_owned(), EmbeddedFs::from_files(&[
+        ("game/main.glsl", "#include_once \"@engine/noise.glsl\"\n#include_once <@engine/lighting>"),
+        ("engine/noise.glsl", "float noise;"),
+        ("engine/lighting", "float engine_lighting;"),
+        ("overrides/lighting.glsl", "float override_lighting;"),
+    ]).into_protocol()).unwrap();
+
+    // Aliases are checked in order they were added, so the first matching one wins
+    let file = loader.load_file("mem://game/main.glsl").unwrap();
+    assert_eq!(file.text(), "float noise;\nfloat engine_lighting;");
+
+    // Only whole components match
+    let error = loader.preprocess_source("#include_once \"@engineering.glsl\"", "mem://game/generated.glsl").unwrap_err();
+    assert!(error.contains("game/@engineering.glsl"), "{error}");
+}
+
+#[test]
+fn text_ranges_are_clamped() {
+    let file = FileIncludes::new("a\nb\nc\nd", "main.glsl".to_owned());
+
+    assert_eq!(file.text_range(1, 3), "b\nc");
+    assert_eq!(file.text_range(2, 100), "c\nd");
+    assert_eq!(file.text_range(3, 1), "");
+    assert_eq!(file.text_range(0, 4), file.text());
+}
+
+#[test]
+fn multi_line_edits_keep_mapping() {
+    let loader = loader(&[
+        ("main.glsl", "#version 330\n#include_once \"a.glsl\"\nfloat x;\nfloat y;\nvoid main() {}"),
+        ("a.glsl", "float a;\nfloat a2;"),
+    ]);
+
+    let mut file = loader.load_file("mem://main.glsl").unwrap();
+    // Cuts the end of the include and the line after it
+    file.replace_range_with(2..4, "float patched;\nfloat patched2;\nfloat patched3;", "<patch>".to_owned().into());
+
+    assert_eq!(file.text(), "#version 330\nfloat a;\nfloat patched;\nfloat patched2;\nfloat patched3;\nfloat y;\nvoid main() {}");
+    assert_eq!(file.file_and_line_at(1), Some(("mem://a.glsl".to_owned().into(), 0)));
+    assert_eq!(file.file_and_line_at(3), Some(("<patch>".to_owned().into(), 1)));
+    assert_eq!(file.file_and_line_at(5), Some(("mem://main.glsl".to_owned().into(), 3)));
+
+    file.delete_range(2..5);
+    assert_eq!(file.text(), "#version 330\nfloat a;\nfloat y;\nvoid main() {}");
+    assert_eq!(file.file_and_line_at(3), Some(("mem://main.glsl".to_owned().into(), 4)));
+    assert!(file.check_invariants().is_ok());
+}
+
+#[test]
+fn deleted_include_does_not_shift_next_include() {
+    // Every line is `<file>:<line>`, so its mapping can be checked against its text
+    let assert_mapped = |file: &FileIncludes| {
+        for (line_id, line) in file.text().lines().enumerate() {
+            let (original_file, original_line) = file.file_and_line_at(line_id).unwrap();
+            assert_eq!(format!("{original_file}:{original_line}"), line, "{}", file.debug_dump());
+        }
+    };
+
+    let mut file = FileIncludes::new("root:0\nroot:1\nroot:2", "root".to_owned());
+    file.insert_lines(0, "f1:0", "f1".to_owned().into());
+    file.replace_range_with(1..2, "f2:0\nf2:1", "f2".to_owned().into());
+    file.delete_range(0..1);
+
+    assert_eq!(file.file_and_line_at(0), Some(("f2".to_owned().into(), 0)));
+    assert_mapped(&file);
+
+    file.delete_range(1..3);
+    file.insert_lines(1, "f3:0\nf3:1", "f3".to_owned().into());
+    file.delete_range(0..1);
+    assert_eq!(file.text(), "f3:0\nf3:1\nroot:2");
+    assert_mapped(&file);
+    assert!(file.check_invariants().is_ok());
+}
+
+#[test]
+fn segments_know_their_include_depth() {
+    let loader = loader(&[
+        ("main.glsl", "#include_once \"a.glsl\"\nfloat main;"),
+        ("a.glsl", "#include_once \"b.glsl\""),
+        ("b.glsl", "float b;"),
+    ]);
+
+    let file = loader.load_file("mem://main.glsl").unwrap();
+    let depths: Vec<_> = file.all_segments_at(0).iter()
+        .map(|segment| (segment.original_file.to_string(), segment.depth))
+        .collect();
+    assert_eq!(depths, [("mem://main.glsl".to_owned(), 0), ("mem://a.glsl".to_owned(), 1), ("mem://b.glsl".to_owned(), 2)]);
+    assert_eq!(file.last_segment_at(1).unwrap().depth, 0);
+}
+
+#[test]
+fn missing_entry_point_is_reported() {
+    let with_main = FileIncludes::new("#version 330\nvoid main () {}", "a.glsl".to_owned());
+    assert_eq!(with_main.entry_point_warning(), None);
+
+    let commented = FileIncludes::new("#version 330\n// void main() {}\n/* main() */\nvoid domain() {}", "b.glsl".to_owned());
+    assert_eq!(commented.entry_point_warning().as_deref(), Some("File b.glsl has no entry point (`main` function)"));
+}
+
+#[test]
+fn include_directives_allow_whitespace_variations() {
+    let mut loader = loader(&[
+        ("main.glsl", "#include_once\"a.glsl\"\n  #  include_once   <b.glsl>\n\t#pragma  include_once \"c.glsl\"\n# pragma include_once d.glsl\t\n#include_onced.glsl\n// #include_once \"e.glsl\""),
+        ("a.glsl", "float a;"),
+        ("b.glsl", "float b;"),
+        ("c.glsl", "float c;"),
+        ("d.glsl", "float d;"),
+    ]);
+    loader.add_search_dir("mem://");
+
+    let file = loader.load_file("mem://main.glsl").unwrap();
+    assert_eq!(file.text(), "float a;\nfloat b;\nfloat c;\nfloat d;\n#include_onced.glsl\n// #include_once \"e.glsl\"");
+}
+
+#[cfg(feature = "gl")]
+#[test]
+fn nul_bytes_in_sources_are_errors() {
+    use shader_loader::shader::Shader;
+
+    let error = Shader::from_source_str("void main() {}\0", gl::FRAGMENT_SHADER).err();
+    assert_eq!(error.as_deref(), Some("Shader source contains NUL byte at position 14"));
+}
+
+#[cfg(feature = "gl")]
+#[test]
+fn shader_stages_are_named() {
+    use shader_loader::shader::shader_type_name;
+
+    assert_eq!(shader_type_name(gl::VERTEX_SHADER), "vertex");
+    assert_eq!(shader_type_name(gl::TESS_EVALUATION_SHADER), "tessellation evaluation");
+    assert_eq!(shader_type_name(0x1234), "shader(0x1234)");
+}
+
+#[cfg(feature = "gl")]
+#[test]
+fn stage_is_required_without_pragma() {
+    use shader_loader::program::Program;
+
+    let loader = loader(&[("effect.glsl", "#version 330\n#pragma stages vertex\nvoid main() {}")]);
+    let error = Program::from_loader(&loader, &[("mem://effect.glsl", None)]).err();
+    assert_eq!(error.as_deref(), Some("File mem://effect.glsl has neither stage specified, nor `#pragma stage` line"));
+}
+
+#[cfg(feature = "gl")]
+#[test]
+fn loading_errors_are_returned_before_compilation() {
+    use shader_loader::shader::Shader;
+
+    let loader = loader(&[("main.glsl", "#include_once \"missing.glsl\"\nvoid main() {}")]);
+    let error = Shader::compile(&loader, "mem://main.glsl", gl::FRAGMENT_SHADER, &[("A", "1")]).err().unwrap();
+    assert!(error.contains("missing.glsl"), "{error}");
+}
+
+#[cfg(feature = "gl")]
+#[test]
+fn stage_defines_need_existing_file() {
+    use shader_loader::program::Program;
+
+    let loader = loader(&[("sprite.glsl", "void main() {}")]);
+    let error = Program::from_loader_with_defines(&loader, &[
+        ("mem://sprite.glsl", gl::VERTEX_SHADER, &[("VERTEX", "1")]),
+        ("mem://missing.glsl", gl::FRAGMENT_SHADER, &[("FRAGMENT", "1")]),
+    ]).err().unwrap();
+    assert!(error.contains("missing.glsl"), "{error}");
+}
+
+#[cfg(feature = "gl")]
+#[test]
+fn spirv_requires_loaded_functions() {
+    use shader_loader::program::Program;
+
+    // `load_spirv_with` was not called, so GL is not queried at all
+    let error = Program::from_spirv_shaders(&[(&[0x07, 0x23, 0x02, 0x03], gl::VERTEX_SHADER, "main")]).err();
+    assert_eq!(
+        error.as_deref(),
+        Some("SPIR-V vertex stage :: SPIR-V shaders are not supported: GL_ARB_gl_spirv is unavailable or `load_spirv_with` was not called")
+    );
+}
+
+#[test]
+fn version_is_detected() {
+    let version = |text: &str| FileIncludes::new(text, "main.glsl".to_owned()).detected_version();
+
+    assert_eq!(version("// header\n  #version 450 core\nvoid main() {}"), Some((450, Some("core".to_owned()))));
+    assert_eq!(version("#version 300 es"), Some((300, Some("es".to_owned()))));
+    assert_eq!(version("#version 330 // comment"), Some((330, None)));
+    assert_eq!(version("#version 330 /* core */"), Some((330, None)));
+    assert_eq!(version("/*\n#version 100\n*/\n#version 450"), Some((450, None)));
+    assert_eq!(version("// #version 100\n#version 310 es"), Some((310, Some("es".to_owned()))));
+    assert_eq!(version("#version abc"), None);
+    assert_eq!(version("void main() {}"), None);
+}
+
+#[cfg(feature = "serde")]
+#[test]
+fn preprocessed_text_is_exported_with_sourcemap() {
+    let loader = loader(&[
+        ("main.glsl", "#include_once \"a.glsl\"\nvoid main() {}"),
+        ("a.glsl", "float a;"),
+    ]);
+
+    let json: serde_json::Value = serde_json::from_str(&loader.preprocess_to_json("mem://main.glsl").unwrap()).unwrap();
+    assert_eq!(json, serde_json::json!({
+        "text": "float a;\nvoid main() {}",
+        "sourcemap": [{ "file": "mem://a.glsl", "line": 0 }, { "file": "mem://main.glsl", "line": 1 }],
+    }));
+}
+
+#[cfg(feature = "gl")]
+#[test]
+fn invalid_shader_types_are_rejected() {
+    use shader_loader::shader::Shader;
+
+    let error = Shader::from_source_str("void main() {}", gl::TEXTURE_2D).err();
+    assert_eq!(error.as_deref(), Some("Invalid shader type: 0x0DE1"));
+
+    let error = Shader::from_sources(&["#version 330\n", "void main() {}"], gl::TEXTURE_2D).err();
+    assert_eq!(error.as_deref(), Some("Invalid shader type: 0x0DE1"));
+}
+
+#[cfg(feature = "net")]
+#[test]
+fn files_are_loaded_over_http() {
+    use std::{io::{BufRead, BufReader, Write}, net::TcpListener};
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let address = listener.local_addr().unwrap();
+    let server = std::thread::spawn(move || {
+        for stream in listener.incoming().take(3) {
+            let mut stream = stream.unwrap();
+            let mut request_line = String::new();
+            let mut reader = BufReader::new(&stream);
+            reader.read_line(&mut request_line).unwrap();
+            while reader.read_line(&mut String::new()).unwrap() > 2 {} // Skip headers
+
+            let (status, body) = match request_line.split_whitespace().nth(1) {
+                Some("/shaders/main.glsl") => ("200 OK", "#include_once \"lib.glsl\"\nvoid main() {}"),
+                Some("/shaders/lib.glsl") => ("200 OK", "float lib;"),
+                _ => ("404 Not Found", ""),
+            };
+            write!(stream, "HTTP/1.1 {status}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}", body.len()).unwrap();
+        }
+    });
+
+    let loader = FileLoader::new().with_http();
+    let file = loader.load_file(&format!("http://{address}/shaders/main.glsl")).unwrap();
+    assert_eq!(file.text(), "float lib;\nvoid main() {}");
+
+    let error = loader.load_file(&format!("http://{address}/missing.glsl")).unwrap_err();
+    assert_eq!(error, format!("File http://{address}/missing.glsl is not found"));
+    server.join().unwrap();
+}